@@ -0,0 +1,77 @@
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use toy_dns_server::{BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType};
+
+const ITERATIONS: u32 = 100_000;
+const WARMUP_ITERATIONS: u32 = 10_000;
+/// Each benchmark is timed this many times and the median run reported, so
+/// one run disturbed by the scheduler doesn't skew the result.
+const RUNS: usize = 7;
+
+fn small_packet() -> DnsPacket {
+    let data = include_bytes!("../response_packet.txt");
+    let mut buffer = BytePacketBuffer::from_bytes(data).unwrap();
+    DnsPacket::from_buffer(&mut buffer).unwrap()
+}
+
+fn large_packet() -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = 6666;
+    packet.header.response = true;
+    packet
+        .questions
+        .push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+
+    for i in 0..16 {
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, i),
+            ttl: 300,
+        });
+    }
+
+    packet
+}
+
+fn bench<F: FnMut()>(name: &str, mut f: F) {
+    for _ in 0..WARMUP_ITERATIONS {
+        f();
+    }
+
+    let mut runs: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..ITERATIONS {
+                f();
+            }
+            start.elapsed()
+        })
+        .collect();
+    runs.sort();
+    let median = runs[RUNS / 2];
+
+    let ops = ITERATIONS as f64 / median.max(Duration::from_nanos(1)).as_secs_f64();
+    println!("{:<16} {:>12.0} ops/sec", name, ops);
+}
+
+fn main() {
+    for (name, mut packet) in [("small", small_packet()), ("large", large_packet())] {
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let len = buffer.position();
+
+        bench(&format!("{} write", name), || {
+            let mut buffer = BytePacketBuffer::new();
+            black_box(&mut packet).write(&mut buffer).unwrap();
+            black_box(buffer.position());
+        });
+
+        bench(&format!("{} parse", name), || {
+            buffer.seek(0).unwrap();
+            black_box(DnsPacket::from_buffer(black_box(&mut buffer)).unwrap());
+        });
+
+        println!("{:<16} {:>12} bytes", format!("{} size", name), len);
+    }
+}
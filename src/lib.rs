@@ -1,6 +1,60 @@
-use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+use anyhow::Context;
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use std::io::{self, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Condvar, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
+thread_local! {
+    // Identifies the query being served on this thread in log lines.
+    static QUERY_TAG: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        println!("{} {}", log_prefix(), format_args!($($arg)*))
+    };
+}
+
+fn log_prefix() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let timestamp = format!("{}.{:03}", now.as_secs(), now.subsec_millis());
+
+    QUERY_TAG.with(|tag| match &*tag.borrow() {
+        Some(tag) => format!("{} [{}]", timestamp, tag),
+        None => timestamp,
+    })
+}
+
+struct QueryTagGuard;
+
+impl QueryTagGuard {
+    fn set(tag: String) -> Self {
+        QUERY_TAG.with(|current| *current.borrow_mut() = Some(tag));
+        QueryTagGuard
+    }
+}
+
+impl Drop for QueryTagGuard {
+    fn drop(&mut self) {
+        QUERY_TAG.with(|current| *current.borrow_mut() = None);
+    }
+}
+
 #[derive(Debug, Error)]
 enum BytePacketBufferError {
     #[error("End of buffer")]
@@ -9,42 +63,139 @@ enum BytePacketBufferError {
     LimitOfJumpsExceeded(usize),
     #[error("Single label exceeds 63 characters of length")]
     SingleLabelExceedsCharactersOfLength,
+    #[error("Reserved label type: {0:#04x}")]
+    ReservedLabelType(u8),
+    #[error("Compression pointer in question name")]
+    CompressionPointerInQuestion,
+    #[error("Character string of {0} bytes exceeds 255")]
+    CharacterStringTooLong(usize),
+}
+
+#[derive(Debug, Error)]
+enum DnsPacketError {
+    #[error("Declared section counts do not fit in {0} bytes")]
+    SectionCountsExceedLength(usize),
+    #[error("Too many records in {0} section: {1}")]
+    SectionTooLarge(&'static str, usize),
+    #[error("{0} record declares {1} bytes of rdata but {2} were read")]
+    RdataLengthMismatch(QueryType, u16, usize),
+}
+
+#[derive(Debug, Error)]
+enum TransportError {
+    #[error("Sent {0} of {1} bytes")]
+    PartialSend(usize, usize),
+    #[error("Response from {0} echoes a client cookie we didn't send")]
+    ClientCookieMismatch(Ipv4Addr),
+    #[error("Discarding response with the wrong {0}")]
+    MismatchedResponse(&'static str),
+}
+
+/// The sending half of a datagram socket, so partial sends can be tested
+/// without a real one.
+trait DatagramSocket {
+    fn send_to<A: ToSocketAddrs>(&self, data: &[u8], addr: A) -> io::Result<usize>;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn send_to<A: ToSocketAddrs>(&self, data: &[u8], addr: A) -> io::Result<usize> {
+        UdpSocket::send_to(self, data, addr)
+    }
+}
+
+fn send_all<S: DatagramSocket, A: ToSocketAddrs>(
+    socket: &S,
+    data: &[u8],
+    addr: A,
+) -> anyhow::Result<()> {
+    let sent = socket.send_to(data, addr)?;
+    if sent != data.len() {
+        return Err(TransportError::PartialSend(sent, data.len()).into());
+    }
+
+    Ok(())
 }
 
 const MAX_BUFFER_SIZE: usize = 512;
+const MAX_TCP_MESSAGE_SIZE: usize = 65535;
 
 #[derive(Debug)]
-struct BytePacketBuffer {
-    buffer: [u8; MAX_BUFFER_SIZE],
+pub struct BytePacketBuffer {
+    buffer: Vec<u8>,
     position: usize,
+    lowercase_names: bool,
+    reject_question_pointers: bool,
 }
 
 impl Default for BytePacketBuffer {
     fn default() -> Self {
-        Self {
-            buffer: [0; MAX_BUFFER_SIZE],
-            position: 0,
-        }
+        Self::with_size(MAX_BUFFER_SIZE)
     }
 }
 
 impl BytePacketBuffer {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self::default()
     }
 
-    fn step(&mut self, steps: usize) -> anyhow::Result<()> {
+    /// Creates a buffer holding up to `size` bytes, for messages that aren't
+    /// bound by the 512-byte UDP limit.
+    pub fn with_size(size: usize) -> Self {
+        Self {
+            buffer: vec![0; size],
+            position: 0,
+            lowercase_names: true,
+            reject_question_pointers: false,
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() > MAX_TCP_MESSAGE_SIZE {
+            return Err(BytePacketBufferError::EndOfBuffer.into());
+        }
+
+        // The buffer ends where the data does, so a message cut off midway
+        // runs out instead of being read on into zeroes.
+        let mut result = Self::with_size(data.len());
+        result.buffer.copy_from_slice(data);
+        Ok(result)
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Rewinds and zeroes the buffer so it can hold the next message without
+    /// reallocating. The name-handling settings are kept.
+    pub fn reset(&mut self) {
+        self.buffer.fill(0);
+        self.position = 0;
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.position
+    }
+
+    pub fn step(&mut self, steps: usize) -> anyhow::Result<()> {
+        if steps > self.remaining() {
+            return Err(BytePacketBufferError::EndOfBuffer.into());
+        }
+
         self.position += steps;
         Ok(())
     }
 
-    fn seek(&mut self, position: usize) -> anyhow::Result<()> {
+    pub fn seek(&mut self, position: usize) -> anyhow::Result<()> {
+        if position > self.buffer.len() {
+            return Err(BytePacketBufferError::EndOfBuffer.into());
+        }
+
         self.position = position;
         Ok(())
     }
 
-    fn read(&mut self) -> anyhow::Result<u8> {
-        if self.position >= MAX_BUFFER_SIZE {
+    pub fn read(&mut self) -> anyhow::Result<u8> {
+        if self.position >= self.buffer.len() {
             return Err(BytePacketBufferError::EndOfBuffer.into());
         }
 
@@ -54,7 +205,7 @@ impl BytePacketBuffer {
     }
 
     fn get(&mut self, position: usize) -> anyhow::Result<u8> {
-        if position >= MAX_BUFFER_SIZE {
+        if position >= self.buffer.len() {
             return Err(BytePacketBufferError::EndOfBuffer.into());
         }
 
@@ -62,25 +213,31 @@ impl BytePacketBuffer {
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> anyhow::Result<&[u8]> {
-        if start + len >= MAX_BUFFER_SIZE {
+        if start + len > self.buffer.len() {
             return Err(BytePacketBufferError::EndOfBuffer.into());
         }
 
-        Ok(&self.buffer[start..start + len as usize])
+        Ok(&self.buffer[start..start + len])
     }
 
-    fn read_u16(&mut self) -> anyhow::Result<u16> {
+    pub fn read_u16(&mut self) -> anyhow::Result<u16> {
         Ok(((self.read()? as u16) << 8) | (self.read()? as u16))
     }
 
-    fn read_u32(&mut self) -> anyhow::Result<u32> {
+    pub fn read_u32(&mut self) -> anyhow::Result<u32> {
         Ok(((self.read()? as u32) << 24)
             | ((self.read()? as u32) << 16)
             | ((self.read()? as u32) << 8)
             | (self.read()? as u32))
     }
 
-    fn read_qname(&mut self, out: &mut String) -> anyhow::Result<()> {
+    pub fn read_bytes(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        let bytes = self.get_range(self.position, len)?.to_vec();
+        self.step(len)?;
+        Ok(bytes)
+    }
+
+    pub fn read_qname(&mut self, out: &mut String) -> anyhow::Result<()> {
         let mut position = self.position;
 
         let mut jumped = false;
@@ -109,6 +266,10 @@ impl BytePacketBuffer {
                 jump_performed += 1;
 
                 continue;
+            } else if (len & 0xC0) != 0 {
+                // 0x40 and 0x80 prefixes are reserved for label types that
+                // were never deployed; they aren't lengths.
+                return Err(BytePacketBufferError::ReservedLabelType(len).into());
             } else {
                 position += 1;
 
@@ -116,11 +277,15 @@ impl BytePacketBuffer {
                     break;
                 }
 
+                let lowercase_names = self.lowercase_names;
+                let label = self.get_range(position, len as usize)?;
+
                 out.push_str(delim);
-                out.push_str(
-                    &String::from_utf8_lossy(self.get_range(position, len as usize)?)
-                        .to_lowercase(),
-                );
+                if lowercase_names {
+                    out.push_str(&escape_label(&label.to_ascii_lowercase()));
+                } else {
+                    out.push_str(&escape_label(label));
+                }
 
                 delim = ".";
                 position += len as usize;
@@ -135,7 +300,7 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, value: u8) -> anyhow::Result<()> {
-        if self.position >= MAX_BUFFER_SIZE {
+        if self.position >= self.buffer.len() {
             return Err(BytePacketBufferError::EndOfBuffer.into());
         }
 
@@ -144,18 +309,33 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
+    fn check_uncompressed_qname(&mut self) -> anyhow::Result<()> {
+        let mut position = self.position;
+
+        loop {
+            let len = self.get(position)?;
+            if (len & 0xC0) == 0xC0 {
+                return Err(BytePacketBufferError::CompressionPointerInQuestion.into());
+            }
+            if len == 0 {
+                return Ok(());
+            }
+            position += len as usize + 1;
+        }
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
         self.write(value)?;
         Ok(())
     }
 
-    fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+    pub fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
         self.write((value >> 8) as u8)?;
         self.write((value & 0xFF) as u8)?;
         Ok(())
     }
 
-    fn write_u32(&mut self, value: u32) -> anyhow::Result<()> {
+    pub fn write_u32(&mut self, value: u32) -> anyhow::Result<()> {
         self.write(((value >> 24) & 0xFF) as u8)?;
         self.write(((value >> 16) & 0xFF) as u8)?;
         self.write(((value >> 8) & 0xFF) as u8)?;
@@ -163,17 +343,25 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    fn write_qname(&mut self, qname: &str) -> anyhow::Result<()> {
-        for label in qname.split('.') {
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        for b in bytes {
+            self.write_u8(*b)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `qname` as a full sequence of labels. Names are never
+    /// compressed on output, so clients that mishandle pointers can still
+    /// read everything we send.
+    pub fn write_qname(&mut self, qname: &str) -> anyhow::Result<()> {
+        for label in name_labels(qname)? {
             let len = label.len();
             if len > 0x3f {
                 return Err(BytePacketBufferError::SingleLabelExceedsCharactersOfLength.into());
             }
 
             self.write_u8(len as u8)?;
-            for b in label.as_bytes() {
-                self.write_u8(*b)?;
-            }
+            self.write_bytes(&label)?;
         }
 
         self.write_u8(0)?;
@@ -181,11 +369,15 @@ impl BytePacketBuffer {
     }
 
     fn set(&mut self, position: usize, value: u8) -> anyhow::Result<()> {
+        if position >= self.buffer.len() {
+            return Err(BytePacketBufferError::EndOfBuffer.into());
+        }
+
         self.buffer[position] = value;
         Ok(())
     }
 
-    fn set_u16(&mut self, position: usize, value: u16) -> anyhow::Result<()> {
+    pub fn set_u16(&mut self, position: usize, value: u16) -> anyhow::Result<()> {
         self.set(position, (value >> 8) as u8)?;
         self.set(position + 1, (value & 0xFF) as u8)?;
         Ok(())
@@ -193,13 +385,15 @@ impl BytePacketBuffer {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum ResultCode {
+pub enum ResultCode {
     NoError,
     FormErr,
     ServFail,
     NxDomain,
     NotImp,
     Refused,
+    NotAuth = 9,
+    NotZone = 10,
 }
 
 impl From<u8> for ResultCode {
@@ -210,28 +404,46 @@ impl From<u8> for ResultCode {
             3 => ResultCode::NxDomain,
             4 => ResultCode::NotImp,
             5 => ResultCode::Refused,
+            9 => ResultCode::NotAuth,
+            10 => ResultCode::NotZone,
             _ => ResultCode::NoError,
         }
     }
 }
 
+impl fmt::Display for ResultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ResultCode::NoError => "NOERROR",
+            ResultCode::FormErr => "FORMERR",
+            ResultCode::ServFail => "SERVFAIL",
+            ResultCode::NxDomain => "NXDOMAIN",
+            ResultCode::NotImp => "NOTIMP",
+            ResultCode::Refused => "REFUSED",
+            ResultCode::NotAuth => "NOTAUTH",
+            ResultCode::NotZone => "NOTZONE",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Debug, Clone)]
-struct DnsHeader {
-    id: u16,
-    recursion_desired: bool,
-    truncated_message: bool,
-    authoritative_answer: bool,
-    opcode: u8,
-    response: bool,
-    rescode: ResultCode,
-    checking_disabled: bool,
-    authentic_data: bool,
-    z: bool,
-    recursion_available: bool,
-    questions: u16,
-    answers: u16,
-    authoritative_entries: u16,
-    resource_entries: u16,
+pub struct DnsHeader {
+    pub id: u16,
+    pub recursion_desired: bool,
+    pub truncated_message: bool,
+    pub authoritative_answer: bool,
+    pub opcode: u8,
+    pub response: bool,
+    pub rescode: ResultCode,
+    pub checking_disabled: bool,
+    pub authentic_data: bool,
+    pub z: bool,
+    pub recursion_available: bool,
+    pub questions: u16,
+    pub answers: u16,
+    pub authoritative_entries: u16,
+    pub resource_entries: u16,
 }
 
 impl Default for DnsHeader {
@@ -257,10 +469,56 @@ impl Default for DnsHeader {
 }
 
 impl DnsHeader {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self::default()
     }
 
+    pub fn is_query(&self) -> bool {
+        !self.response
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.response
+    }
+
+    /// Decodes a header on its own, without needing the rest of the message.
+    pub fn from_bytes(bytes: &[u8; 12]) -> Self {
+        let mut buffer = BytePacketBuffer::with_size(12);
+        buffer.buffer.copy_from_slice(bytes);
+
+        // Twelve bytes are always enough for a header.
+        let mut header = Self::new();
+        header.read(&mut buffer).unwrap();
+        header
+    }
+
+    pub fn to_bytes(&self) -> [u8; 12] {
+        let mut buffer = BytePacketBuffer::with_size(12);
+        self.clone().write(&mut buffer).unwrap();
+
+        let mut bytes = [0; 12];
+        bytes.copy_from_slice(&buffer.buffer);
+        bytes
+    }
+
+    /// The set flags in dig's notation, e.g. `qr rd ra`.
+    pub fn flags_string(&self) -> String {
+        [
+            (self.response, "qr"),
+            (self.authoritative_answer, "aa"),
+            (self.truncated_message, "tc"),
+            (self.recursion_desired, "rd"),
+            (self.recursion_available, "ra"),
+            (self.authentic_data, "ad"),
+            (self.checking_disabled, "cd"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+
     fn read(&mut self, buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
         self.id = buffer.read_u16()?;
 
@@ -296,11 +554,11 @@ impl DnsHeader {
                 | (self.opcode << 3)
                 | ((self.response as u8) << 7),
         )?;
+        // The Z bit is reserved and must be zero in all queries and responses.
         buffer.write_u8(
             (self.rescode as u8)
                 | ((self.checking_disabled as u8) << 4)
                 | ((self.authentic_data as u8) << 5)
-                | ((self.z as u8) << 6)
                 | ((self.recursion_available as u8) << 7),
         )?;
         buffer.write_u16(self.questions)?;
@@ -312,13 +570,27 @@ impl DnsHeader {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum QueryType {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueryType {
     A,
     Ns,
     Cname,
+    Soa,
     Mx,
     Aaaa,
+    Opt,
+    Sshfp,
+    Uri,
+    Nsec,
+    Txt,
+    Hinfo,
+    Cds,
+    Cdnskey,
+    Tsig,
+    Dname,
+    Cert,
+    Axfr,
+    Any,
     Unknown(u16),
 }
 
@@ -328,8 +600,22 @@ impl From<u16> for QueryType {
             1 => QueryType::A,
             2 => QueryType::Ns,
             5 => QueryType::Cname,
+            6 => QueryType::Soa,
             15 => QueryType::Mx,
             28 => QueryType::Aaaa,
+            41 => QueryType::Opt,
+            44 => QueryType::Sshfp,
+            256 => QueryType::Uri,
+            47 => QueryType::Nsec,
+            16 => QueryType::Txt,
+            13 => QueryType::Hinfo,
+            252 => QueryType::Axfr,
+            255 => QueryType::Any,
+            59 => QueryType::Cds,
+            60 => QueryType::Cdnskey,
+            250 => QueryType::Tsig,
+            39 => QueryType::Dname,
+            37 => QueryType::Cert,
             _ => QueryType::Unknown(num),
         }
     }
@@ -341,45 +627,456 @@ impl From<QueryType> for u16 {
             QueryType::A => 1,
             QueryType::Ns => 2,
             QueryType::Cname => 5,
+            QueryType::Soa => 6,
             QueryType::Mx => 15,
             QueryType::Aaaa => 28,
+            QueryType::Opt => 41,
+            QueryType::Sshfp => 44,
+            QueryType::Uri => 256,
+            QueryType::Nsec => 47,
+            QueryType::Txt => 16,
+            QueryType::Hinfo => 13,
+            QueryType::Axfr => 252,
+            QueryType::Any => 255,
+            QueryType::Cds => 59,
+            QueryType::Cdnskey => 60,
+            QueryType::Tsig => 250,
+            QueryType::Dname => 39,
+            QueryType::Cert => 37,
             QueryType::Unknown(num) => num,
         }
     }
 }
 
+#[derive(Debug, Error)]
+pub enum QueryTypeError {
+    #[error("Unknown query type: {0}")]
+    UnknownQueryType(String),
+    #[error("Query type {0} already has a built-in codec")]
+    AlreadyDefined(u16),
+}
+
+impl QueryType {
+    pub fn to_str(&self) -> Option<&'static str> {
+        match self {
+            QueryType::A => Some("A"),
+            QueryType::Ns => Some("NS"),
+            QueryType::Cname => Some("CNAME"),
+            QueryType::Soa => Some("SOA"),
+            QueryType::Mx => Some("MX"),
+            QueryType::Aaaa => Some("AAAA"),
+            QueryType::Opt => Some("OPT"),
+            QueryType::Sshfp => Some("SSHFP"),
+            QueryType::Uri => Some("URI"),
+            QueryType::Nsec => Some("NSEC"),
+            QueryType::Txt => Some("TXT"),
+            QueryType::Hinfo => Some("HINFO"),
+            QueryType::Axfr => Some("AXFR"),
+            QueryType::Any => Some("ANY"),
+            QueryType::Cds => Some("CDS"),
+            QueryType::Cdnskey => Some("CDNSKEY"),
+            QueryType::Tsig => Some("TSIG"),
+            QueryType::Dname => Some("DNAME"),
+            QueryType::Cert => Some("CERT"),
+            QueryType::Unknown(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.to_str(), self) {
+            (Some(name), _) => write!(f, "{}", name),
+            (None, qtype) => write!(f, "TYPE{}", u16::from(*qtype)),
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = QueryTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+
+        let qtype = match upper.as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::Ns,
+            "CNAME" => QueryType::Cname,
+            "SOA" => QueryType::Soa,
+            "MX" => QueryType::Mx,
+            "AAAA" => QueryType::Aaaa,
+            "OPT" => QueryType::Opt,
+            "SSHFP" => QueryType::Sshfp,
+            "URI" => QueryType::Uri,
+            "NSEC" => QueryType::Nsec,
+            "TXT" => QueryType::Txt,
+            "HINFO" => QueryType::Hinfo,
+            "AXFR" => QueryType::Axfr,
+            "ANY" => QueryType::Any,
+            "CDS" => QueryType::Cds,
+            "CDNSKEY" => QueryType::Cdnskey,
+            "TSIG" => QueryType::Tsig,
+            "DNAME" => QueryType::Dname,
+            "CERT" => QueryType::Cert,
+            _ => match upper.strip_prefix("TYPE").map(str::parse::<u16>) {
+                Some(Ok(num)) => QueryType::from(num),
+                _ => return Err(QueryTypeError::UnknownQueryType(s.to_string())),
+            },
+        };
+
+        Ok(qtype)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum QueryClass {
+    #[default]
+    In,
+    Ch,
+    None,
+    Any,
+    Unknown(u16),
+}
+
+impl From<u16> for QueryClass {
+    fn from(num: u16) -> Self {
+        match num {
+            1 => QueryClass::In,
+            3 => QueryClass::Ch,
+            254 => QueryClass::None,
+            255 => QueryClass::Any,
+            _ => QueryClass::Unknown(num),
+        }
+    }
+}
+
+impl From<QueryClass> for u16 {
+    fn from(qclass: QueryClass) -> Self {
+        match qclass {
+            QueryClass::In => 1,
+            QueryClass::Ch => 3,
+            QueryClass::None => 254,
+            QueryClass::Any => 255,
+            QueryClass::Unknown(num) => num,
+        }
+    }
+}
+
+impl QueryClass {
+    /// Whether a question of this class asks for records of `class`. ANY
+    /// asks for every class.
+    pub fn matches(self, class: QueryClass) -> bool {
+        self == QueryClass::Any || self == class
+    }
+}
+
+impl fmt::Display for QueryClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryClass::In => write!(f, "IN"),
+            QueryClass::Ch => write!(f, "CH"),
+            QueryClass::None => write!(f, "NONE"),
+            QueryClass::Any => write!(f, "ANY"),
+            QueryClass::Unknown(num) => write!(f, "CLASS{}", num),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum NameError {
+    #[error("Invalid escape sequence in name: {0}")]
+    InvalidEscape(String),
+    #[error("Empty label in name: {0}")]
+    EmptyLabel(String),
+    #[error("Invalid hostname: {0:?}")]
+    InvalidHostname(String),
+}
+
+/// Splits a name in presentation format into the bytes of its labels,
+/// decoding `\.`-style and `\DDD` escapes. A trailing dot is optional.
+fn name_labels(name: &str) -> Result<Vec<Vec<u8>>, NameError> {
+    if name == "." {
+        return Ok(Vec::new());
+    }
+
+    let invalid = || NameError::InvalidEscape(name.to_string());
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut bytes = name.bytes();
+
+    while let Some(b) = bytes.next() {
+        match b {
+            // An empty label would end the name early on the wire.
+            b'.' if label.is_empty() => return Err(NameError::EmptyLabel(name.to_string())),
+            b'.' => labels.push(std::mem::take(&mut label)),
+            b'\\' => match bytes.next() {
+                Some(d) if d.is_ascii_digit() => {
+                    let digits = [Some(d), bytes.next(), bytes.next()];
+                    let value = digits
+                        .iter()
+                        .map(|digit| digit.filter(u8::is_ascii_digit).map(|digit| digit - b'0'))
+                        .try_fold(0u32, |acc, digit| {
+                            digit.map(|digit| acc * 10 + digit as u32)
+                        });
+
+                    match value {
+                        Some(value) if value <= 255 => label.push(value as u8),
+                        _ => return Err(invalid()),
+                    }
+                }
+                Some(escaped) => label.push(escaped),
+                None => return Err(invalid()),
+            },
+            b => label.push(b),
+        }
+    }
+
+    if !label.is_empty() {
+        labels.push(label);
+    }
+
+    Ok(labels)
+}
+
+/// Writes a label in presentation format, escaping dots, backslashes and
+/// anything that isn't printable ASCII.
+fn escape_label(label: &[u8]) -> String {
+    let mut out = String::new();
+
+    for &b in label {
+        match b {
+            b'.' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            0x21..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\{:03}", b)),
+        }
+    }
+
+    out
+}
+
+pub fn normalize_name(name: &str) -> Result<String, NameError> {
+    let labels = name_labels(name)?
+        .iter()
+        .map(|label| escape_label(&label.to_ascii_lowercase()))
+        .collect::<Vec<_>>();
+
+    Ok(labels.join("."))
+}
+
+pub fn label_count(name: &str) -> usize {
+    name_labels(name).map_or(0, |labels| labels.len())
+}
+
+/// Checks `name` against the hostname rules of RFC 1123: at most 253
+/// characters, labels of 1 to 63 letters, digits and inner hyphens.
+pub fn is_valid_hostname(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+fn fqdn(name: &str) -> String {
+    format!("{}.", name)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+#[derive(Debug, Error)]
+pub enum DigArgsError {
+    #[error("Missing query name")]
+    MissingName,
+    #[error("Unexpected argument: {0}")]
+    UnexpectedArgument(String),
+    #[error("Invalid server address: {0}")]
+    InvalidServer(String),
+    #[error("Invalid query name: {0}")]
+    InvalidName(String),
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct DnsQuestion {
-    name: String,
-    qtype: QueryType,
+pub struct DigQuery {
+    pub question: DnsQuestion,
+    pub server: Option<IpAddr>,
+    pub recursion_desired: bool,
+}
+
+pub fn parse_dig_args<I, S>(args: I) -> Result<DigQuery, DigArgsError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut name = None;
+    let mut qtype = None;
+    let mut server = None;
+    let mut recursion_desired = true;
+
+    for arg in args {
+        let arg = arg.as_ref();
+
+        if let Some(addr) = arg.strip_prefix('@') {
+            let addr = addr
+                .parse::<IpAddr>()
+                .map_err(|_| DigArgsError::InvalidServer(addr.to_string()))?;
+            server = Some(addr);
+        } else if arg == "+rec" {
+            recursion_desired = true;
+        } else if arg == "+norec" {
+            recursion_desired = false;
+        } else if let (None, Ok(parsed)) = (qtype, arg.parse::<QueryType>()) {
+            qtype = Some(parsed);
+        } else if name.is_none() {
+            let normalized =
+                normalize_name(arg).map_err(|_| DigArgsError::InvalidName(arg.to_string()))?;
+            name = Some(normalized);
+        } else {
+            return Err(DigArgsError::UnexpectedArgument(arg.to_string()));
+        }
+    }
+
+    let name = name.ok_or(DigArgsError::MissingName)?;
+
+    Ok(DigQuery {
+        question: DnsQuestion::new(name, qtype.unwrap_or(QueryType::A)),
+        server,
+        recursion_desired,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuestion {
+    pub name: String,
+    pub qtype: QueryType,
+    pub qclass: QueryClass,
 }
 
 impl DnsQuestion {
-    fn new(name: String, qtype: QueryType) -> Self {
-        DnsQuestion { name, qtype }
+    pub fn new(name: String, qtype: QueryType) -> Self {
+        DnsQuestion {
+            name,
+            qtype,
+            qclass: QueryClass::In,
+        }
     }
 
     fn read(&mut self, buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
+        if buffer.reject_question_pointers {
+            buffer.check_uncompressed_qname()?;
+        }
+
         buffer.read_qname(&mut self.name)?;
         self.qtype = QueryType::from(buffer.read_u16()?);
-        let _ = buffer.read_u16()?;
+        self.qclass = QueryClass::from(buffer.read_u16()?);
 
         Ok(())
     }
 
+    pub fn matches(&self, record: &DnsRecord) -> bool {
+        // Every record held here is in the Internet class.
+        if !self.qclass.matches(QueryClass::In) || !record.domain().eq_ignore_ascii_case(&self.name)
+        {
+            return false;
+        }
+
+        match (self.qtype, record.query_type()) {
+            (QueryType::Any, _) => true,
+            (QueryType::A | QueryType::Aaaa, QueryType::Cname) => true,
+            (qtype, record_type) => qtype == record_type,
+        }
+    }
+
     fn write(&self, buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
         buffer.write_qname(&self.name)?;
 
         let type_num = self.qtype.into();
         buffer.write_u16(type_num)?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.qclass.into())?;
 
         Ok(())
     }
 }
 
+pub const EDNS_OPTION_CLIENT_SUBNET: u16 = 8;
+pub const EDNS_OPTION_COOKIE: u16 = 10;
+pub const EDNS_OPTION_PADDING: u16 = 12;
+pub const EDNS_OPTION_EXTENDED_ERROR: u16 = 15;
+pub const EDNS_FLAG_DNSSEC_OK: u32 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl EdnsOption {
+    pub fn extended_error(info_code: u16, extra_text: &str) -> Self {
+        let mut data = info_code.to_be_bytes().to_vec();
+        data.extend_from_slice(extra_text.as_bytes());
+
+        Self {
+            code: EDNS_OPTION_EXTENDED_ERROR,
+            data,
+        }
+    }
+
+    /// An EDNS Client Subnet option for `subnet` (RFC 7871). Responses set
+    /// `scope_prefix_len` to how much of the address the answer depends on.
+    pub fn client_subnet(subnet: Subnet, scope_prefix_len: u8) -> Self {
+        let (family, octets) = match subnet.addr {
+            IpAddr::V4(addr) => (1u16, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2u16, addr.octets().to_vec()),
+        };
+
+        let mut data = family.to_be_bytes().to_vec();
+        data.push(subnet.prefix_len);
+        data.push(scope_prefix_len);
+        data.extend_from_slice(&octets[..(subnet.prefix_len as usize).div_ceil(8)]);
+
+        Self {
+            code: EDNS_OPTION_CLIENT_SUBNET,
+            data,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum DnsRecord {
+pub enum DnsRecord {
     A {
         domain: String,
         addr: Ipv4Addr,
@@ -395,6 +1092,17 @@ enum DnsRecord {
         host: String,
         ttl: u32,
     },
+    Soa {
+        domain: String,
+        m_name: String,
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
     Mx {
         domain: String,
         priority: u16,
@@ -406,41 +1114,438 @@ enum DnsRecord {
         addr: Ipv6Addr,
         ttl: u32,
     },
-    Unknown {
+    Sshfp {
         domain: String,
-        qtype: u16,
-        data_len: u16,
+        algorithm: u8,
+        fp_type: u8,
+        fingerprint: Vec<u8>,
         ttl: u32,
     },
-}
-
-impl DnsRecord {
-    fn read(buffer: &mut BytePacketBuffer) -> anyhow::Result<DnsRecord> {
-        let mut domain = String::new();
-        buffer.read_qname(&mut domain)?;
-
-        let qtype_num = buffer.read_u16()?;
-        let qtype = qtype_num.into();
-        let _ = buffer.read_u16()?;
-        let ttl = buffer.read_u32()?;
-        let data_len = buffer.read_u16()?;
-
-        match qtype {
-            QueryType::A => {
-                let raw_addr = buffer.read_u32()?;
-
-                Ok(DnsRecord::A {
-                    domain,
-                    addr: Ipv4Addr::new(
-                        ((raw_addr >> 24) & 0xFF) as u8,
-                        ((raw_addr >> 16) & 0xFF) as u8,
-                        ((raw_addr >> 8) & 0xFF) as u8,
-                        (raw_addr & 0xFF) as u8,
-                    ),
-                    ttl,
-                })
-            }
-            QueryType::Ns => {
+    Uri {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        target: String,
+        ttl: u32,
+    },
+    Nsec {
+        domain: String,
+        next_domain: String,
+        type_bitmap: Vec<u8>,
+        ttl: u32,
+    },
+    Txt {
+        domain: String,
+        data: Vec<String>,
+        ttl: u32,
+    },
+    Hinfo {
+        domain: String,
+        cpu: String,
+        os: String,
+        ttl: u32,
+    },
+    Cds {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    },
+    Cdnskey {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    },
+    Tsig {
+        domain: String,
+        algorithm: String,
+        time_signed: u64,
+        fudge: u16,
+        mac: Vec<u8>,
+        original_id: u16,
+        error: u16,
+        other: Vec<u8>,
+    },
+    Dname {
+        domain: String,
+        target: String,
+        ttl: u32,
+    },
+    Cert {
+        domain: String,
+        cert_type: u16,
+        key_tag: u16,
+        algorithm: u8,
+        certificate: Vec<u8>,
+        ttl: u32,
+    },
+    Opt {
+        packet_len: u16,
+        flags: u32,
+        options: Vec<EdnsOption>,
+    },
+    Unknown {
+        domain: String,
+        qtype: u16,
+        data: Vec<u8>,
+        ttl: u32,
+    },
+}
+
+fn valid_hostname(name: &str) -> Result<String, NameError> {
+    if !is_valid_hostname(name) {
+        return Err(NameError::InvalidHostname(name.to_string()));
+    }
+
+    Ok(name.strip_suffix('.').unwrap_or(name).to_string())
+}
+
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            ";{}\t\t{}\t{}",
+            fqdn(&self.name),
+            self.qclass,
+            self.qtype
+        )
+    }
+}
+
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (ttl, rdata) = match self {
+            DnsRecord::A { addr, ttl, .. } => (ttl, addr.to_string()),
+            DnsRecord::Ns { host, ttl, .. } | DnsRecord::Cname { host, ttl, .. } => {
+                (ttl, fqdn(host))
+            }
+            DnsRecord::Soa {
+                m_name,
+                r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+                ..
+            } => (
+                ttl,
+                format!(
+                    "{} {} {} {} {} {} {}",
+                    fqdn(m_name),
+                    fqdn(r_name),
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum
+                ),
+            ),
+            DnsRecord::Mx {
+                priority,
+                host,
+                ttl,
+                ..
+            } => (ttl, format!("{} {}", priority, fqdn(host))),
+            DnsRecord::Aaaa { addr, ttl, .. } => (ttl, addr.to_string()),
+            DnsRecord::Sshfp {
+                algorithm,
+                fp_type,
+                fingerprint,
+                ttl,
+                ..
+            } => (
+                ttl,
+                format!("{} {} {}", algorithm, fp_type, hex(fingerprint)),
+            ),
+            DnsRecord::Uri {
+                priority,
+                weight,
+                target,
+                ttl,
+                ..
+            } => (ttl, format!("{} {} \"{}\"", priority, weight, target)),
+            DnsRecord::Nsec {
+                next_domain,
+                type_bitmap,
+                ttl,
+                ..
+            } => (ttl, format!("{} {}", fqdn(next_domain), hex(type_bitmap))),
+            DnsRecord::Txt { data, ttl, .. } => (
+                ttl,
+                data.iter()
+                    .map(|text| format!("\"{}\"", text))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            DnsRecord::Hinfo { cpu, os, ttl, .. } => (ttl, format!("\"{}\" \"{}\"", cpu, os)),
+            DnsRecord::Cds {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ttl,
+                ..
+            } => (
+                ttl,
+                format!("{} {} {} {}", key_tag, algorithm, digest_type, hex(digest)),
+            ),
+            DnsRecord::Cdnskey {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ttl,
+                ..
+            } => (
+                ttl,
+                format!(
+                    "{} {} {} {}",
+                    flags,
+                    protocol,
+                    algorithm,
+                    base64(public_key)
+                ),
+            ),
+            DnsRecord::Tsig {
+                domain,
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+            } => {
+                return write!(
+                    f,
+                    "{}\t0\tANY\tTSIG\t{} {} {} {} {} {} {} {}",
+                    fqdn(domain),
+                    fqdn(algorithm),
+                    time_signed,
+                    fudge,
+                    mac.len(),
+                    base64(mac),
+                    original_id,
+                    ResultCode::from(*error as u8),
+                    other.len()
+                )
+            }
+            DnsRecord::Dname { target, ttl, .. } => (ttl, fqdn(target)),
+            DnsRecord::Cert {
+                cert_type,
+                key_tag,
+                algorithm,
+                certificate,
+                ttl,
+                ..
+            } => (
+                ttl,
+                format!(
+                    "{} {} {} {}",
+                    cert_type,
+                    key_tag,
+                    algorithm,
+                    base64(certificate)
+                ),
+            ),
+            DnsRecord::Opt {
+                packet_len, flags, ..
+            } => {
+                return write!(
+                    f,
+                    "; EDNS: version: {}, udp: {}",
+                    (flags >> 16) & 0xFF,
+                    packet_len
+                )
+            }
+            DnsRecord::Unknown { data, ttl, .. } => {
+                (ttl, format!("\\# {} {}", data.len(), hex(data)))
+            }
+        };
+
+        write!(
+            f,
+            "{}\t{}\tIN\t{}\t{}",
+            fqdn(self.domain()),
+            ttl,
+            self.query_type(),
+            rdata
+        )
+    }
+}
+
+impl DnsRecord {
+    pub fn ns(domain: &str, host: &str, ttl: u32) -> Result<Self, NameError> {
+        Ok(DnsRecord::Ns {
+            domain: domain.to_string(),
+            host: valid_hostname(host)?,
+            ttl,
+        })
+    }
+
+    pub fn cname(domain: &str, host: &str, ttl: u32) -> Result<Self, NameError> {
+        Ok(DnsRecord::Cname {
+            domain: domain.to_string(),
+            host: valid_hostname(host)?,
+            ttl,
+        })
+    }
+
+    pub fn mx(domain: &str, priority: u16, host: &str, ttl: u32) -> Result<Self, NameError> {
+        Ok(DnsRecord::Mx {
+            domain: domain.to_string(),
+            priority,
+            host: valid_hostname(host)?,
+            ttl,
+        })
+    }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::A { domain, .. }
+            | DnsRecord::Ns { domain, .. }
+            | DnsRecord::Cname { domain, .. }
+            | DnsRecord::Soa { domain, .. }
+            | DnsRecord::Mx { domain, .. }
+            | DnsRecord::Aaaa { domain, .. }
+            | DnsRecord::Sshfp { domain, .. }
+            | DnsRecord::Uri { domain, .. }
+            | DnsRecord::Nsec { domain, .. }
+            | DnsRecord::Txt { domain, .. }
+            | DnsRecord::Hinfo { domain, .. }
+            | DnsRecord::Cds { domain, .. }
+            | DnsRecord::Cdnskey { domain, .. }
+            | DnsRecord::Tsig { domain, .. }
+            | DnsRecord::Dname { domain, .. }
+            | DnsRecord::Cert { domain, .. }
+            | DnsRecord::Unknown { domain, .. } => domain,
+            DnsRecord::Opt { .. } => "",
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::Ns { ttl, .. }
+            | DnsRecord::Cname { ttl, .. }
+            | DnsRecord::Soa { ttl, .. }
+            | DnsRecord::Mx { ttl, .. }
+            | DnsRecord::Aaaa { ttl, .. }
+            | DnsRecord::Sshfp { ttl, .. }
+            | DnsRecord::Uri { ttl, .. }
+            | DnsRecord::Nsec { ttl, .. }
+            | DnsRecord::Txt { ttl, .. }
+            | DnsRecord::Hinfo { ttl, .. }
+            | DnsRecord::Cds { ttl, .. }
+            | DnsRecord::Cdnskey { ttl, .. }
+            | DnsRecord::Dname { ttl, .. }
+            | DnsRecord::Cert { ttl, .. }
+            | DnsRecord::Unknown { ttl, .. } => *ttl,
+            DnsRecord::Opt { .. } | DnsRecord::Tsig { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, value: u32) {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::Ns { ttl, .. }
+            | DnsRecord::Cname { ttl, .. }
+            | DnsRecord::Soa { ttl, .. }
+            | DnsRecord::Mx { ttl, .. }
+            | DnsRecord::Aaaa { ttl, .. }
+            | DnsRecord::Sshfp { ttl, .. }
+            | DnsRecord::Uri { ttl, .. }
+            | DnsRecord::Nsec { ttl, .. }
+            | DnsRecord::Txt { ttl, .. }
+            | DnsRecord::Hinfo { ttl, .. }
+            | DnsRecord::Cds { ttl, .. }
+            | DnsRecord::Cdnskey { ttl, .. }
+            | DnsRecord::Dname { ttl, .. }
+            | DnsRecord::Cert { ttl, .. }
+            | DnsRecord::Unknown { ttl, .. } => *ttl = value,
+            DnsRecord::Opt { .. } | DnsRecord::Tsig { .. } => {}
+        }
+    }
+
+    pub fn same_rr(&self, other: &DnsRecord) -> bool {
+        let mut lhs = self.clone();
+        let mut rhs = other.clone();
+        lhs.set_ttl(0);
+        rhs.set_ttl(0);
+        lhs == rhs
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        match self {
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::Ns { .. } => QueryType::Ns,
+            DnsRecord::Cname { .. } => QueryType::Cname,
+            DnsRecord::Soa { .. } => QueryType::Soa,
+            DnsRecord::Mx { .. } => QueryType::Mx,
+            DnsRecord::Aaaa { .. } => QueryType::Aaaa,
+            DnsRecord::Sshfp { .. } => QueryType::Sshfp,
+            DnsRecord::Uri { .. } => QueryType::Uri,
+            DnsRecord::Nsec { .. } => QueryType::Nsec,
+            DnsRecord::Txt { .. } => QueryType::Txt,
+            DnsRecord::Hinfo { .. } => QueryType::Hinfo,
+            DnsRecord::Cds { .. } => QueryType::Cds,
+            DnsRecord::Cdnskey { .. } => QueryType::Cdnskey,
+            DnsRecord::Tsig { .. } => QueryType::Tsig,
+            DnsRecord::Dname { .. } => QueryType::Dname,
+            DnsRecord::Cert { .. } => QueryType::Cert,
+            DnsRecord::Opt { .. } => QueryType::Opt,
+            DnsRecord::Unknown { qtype, .. } => QueryType::from(*qtype),
+        }
+    }
+
+    fn read(buffer: &mut BytePacketBuffer) -> anyhow::Result<DnsRecord> {
+        let mut domain = String::new();
+        buffer.read_qname(&mut domain)?;
+
+        let qtype_num = buffer.read_u16()?;
+        let qtype = qtype_num.into();
+        let class = buffer.read_u16()?;
+        let ttl = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+        let start = buffer.position;
+        let end = start + data_len as usize;
+
+        // Updates and their prerequisites (RFC 2136) name whole RRsets with
+        // empty rdata in the ANY and NONE classes.
+        let class_any_or_none =
+            matches!(QueryClass::from(class), QueryClass::Any | QueryClass::None);
+        if data_len == 0 && class_any_or_none && qtype != QueryType::Opt {
+            return Ok(DnsRecord::Unknown {
+                domain,
+                qtype: qtype_num,
+                data: Vec::new(),
+                ttl,
+            });
+        }
+
+        let record: anyhow::Result<DnsRecord> = match qtype {
+            QueryType::A => {
+                let raw_addr = buffer.read_u32()?;
+
+                Ok(DnsRecord::A {
+                    domain,
+                    addr: Ipv4Addr::new(
+                        ((raw_addr >> 24) & 0xFF) as u8,
+                        ((raw_addr >> 16) & 0xFF) as u8,
+                        ((raw_addr >> 8) & 0xFF) as u8,
+                        (raw_addr & 0xFF) as u8,
+                    ),
+                    ttl,
+                })
+            }
+            QueryType::Ns => {
                 let mut ns = String::new();
                 buffer.read_qname(&mut ns)?;
 
@@ -460,6 +1565,29 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::Soa => {
+                let mut m_name = String::new();
+                buffer.read_qname(&mut m_name)?;
+                let mut r_name = String::new();
+                buffer.read_qname(&mut r_name)?;
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                Ok(DnsRecord::Soa {
+                    domain,
+                    m_name,
+                    r_name,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ttl,
+                })
+            }
             QueryType::Mx => {
                 let priority = buffer.read_u16()?;
                 let mut mx = String::new();
@@ -490,20 +1618,199 @@ impl DnsRecord {
 
                 Ok(DnsRecord::Aaaa { domain, addr, ttl })
             }
-            QueryType::Unknown(_) => {
-                buffer.step(data_len as usize)?;
+            QueryType::Sshfp => {
+                let algorithm = buffer.read()?;
+                let fp_type = buffer.read()?;
+                let fingerprint = buffer.read_bytes((data_len as usize).saturating_sub(2))?;
+
+                Ok(DnsRecord::Sshfp {
+                    domain,
+                    algorithm,
+                    fp_type,
+                    fingerprint,
+                    ttl,
+                })
+            }
+            QueryType::Uri => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let target = buffer.read_bytes((data_len as usize).saturating_sub(4))?;
+
+                Ok(DnsRecord::Uri {
+                    domain,
+                    priority,
+                    weight,
+                    target: String::from_utf8_lossy(&target).into_owned(),
+                    ttl,
+                })
+            }
+            QueryType::Nsec => {
+                let start = buffer.position;
+                let mut next_domain = String::new();
+                buffer.read_qname(&mut next_domain)?;
+                let consumed = buffer.position - start;
+                let type_bitmap =
+                    buffer.read_bytes((data_len as usize).saturating_sub(consumed))?;
+
+                Ok(DnsRecord::Nsec {
+                    domain,
+                    next_domain,
+                    type_bitmap,
+                    ttl,
+                })
+            }
+            QueryType::Txt => {
+                let mut data = Vec::new();
+
+                while buffer.position < end {
+                    let len = buffer.read()?;
+                    let text = buffer.read_bytes(len as usize)?;
+                    data.push(String::from_utf8_lossy(&text).into_owned());
+                }
+
+                Ok(DnsRecord::Txt { domain, data, ttl })
+            }
+            QueryType::Hinfo => {
+                let mut cpu = String::new();
+                let mut os = String::new();
+                for field in [&mut cpu, &mut os] {
+                    let len = buffer.read()?;
+                    let text = buffer.read_bytes(len as usize)?;
+                    *field = String::from_utf8_lossy(&text).into_owned();
+                }
+
+                Ok(DnsRecord::Hinfo {
+                    domain,
+                    cpu,
+                    os,
+                    ttl,
+                })
+            }
+            QueryType::Cds => {
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read()?;
+                let digest_type = buffer.read()?;
+                let digest = buffer.read_bytes((data_len as usize).saturating_sub(4))?;
+
+                Ok(DnsRecord::Cds {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                })
+            }
+            QueryType::Cdnskey => {
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read()?;
+                let algorithm = buffer.read()?;
+                let public_key = buffer.read_bytes((data_len as usize).saturating_sub(4))?;
+
+                Ok(DnsRecord::Cdnskey {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                })
+            }
+            QueryType::Tsig => {
+                let mut algorithm = String::new();
+                buffer.read_qname(&mut algorithm)?;
+                let time_signed = (buffer.read_u16()? as u64) << 32 | buffer.read_u32()? as u64;
+                let fudge = buffer.read_u16()?;
+                let mac_len = buffer.read_u16()?;
+                let mac = buffer.read_bytes(mac_len as usize)?;
+                let original_id = buffer.read_u16()?;
+                let error = buffer.read_u16()?;
+                let other_len = buffer.read_u16()?;
+                let other = buffer.read_bytes(other_len as usize)?;
+
+                Ok(DnsRecord::Tsig {
+                    domain,
+                    algorithm,
+                    time_signed,
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other,
+                })
+            }
+            QueryType::Dname => {
+                let mut target = String::new();
+                buffer.read_qname(&mut target)?;
+
+                Ok(DnsRecord::Dname {
+                    domain,
+                    target,
+                    ttl,
+                })
+            }
+            QueryType::Cert => {
+                let cert_type = buffer.read_u16()?;
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read()?;
+                let certificate = buffer.read_bytes((data_len as usize).saturating_sub(5))?;
+
+                Ok(DnsRecord::Cert {
+                    domain,
+                    cert_type,
+                    key_tag,
+                    algorithm,
+                    certificate,
+                    ttl,
+                })
+            }
+            QueryType::Opt => {
+                let mut options = Vec::new();
+
+                while buffer.position < end {
+                    let code = buffer.read_u16()?;
+                    let len = buffer.read_u16()?;
+                    let data = buffer.read_bytes(len as usize)?;
+                    options.push(EdnsOption { code, data });
+                }
+
+                Ok(DnsRecord::Opt {
+                    packet_len: class,
+                    flags: ttl,
+                    options,
+                })
+            }
+            QueryType::Axfr | QueryType::Any | QueryType::Unknown(_) => {
+                let data = match record_codec(qtype_num) {
+                    Some(codec) => {
+                        let data = codec.read(buffer, data_len)?;
+                        buffer.seek(end)?;
+                        data
+                    }
+                    None => buffer.read_bytes(data_len as usize)?,
+                };
 
                 Ok(DnsRecord::Unknown {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
                     ttl,
                 })
             }
+        };
+        let record = record?;
+
+        if buffer.position != end {
+            let read = buffer.position - start;
+            return Err(DnsPacketError::RdataLengthMismatch(qtype, data_len, read).into());
         }
+
+        Ok(record)
     }
 
-    fn write(&self, buffer: &mut BytePacketBuffer) -> anyhow::Result<usize> {
+    /// Writes the record as belonging to `class`. OPT and TSIG keep the
+    /// class their format gives them.
+    fn write(&self, buffer: &mut BytePacketBuffer, class: QueryClass) -> anyhow::Result<usize> {
         let start = buffer.position;
 
         match *self {
@@ -514,7 +1821,7 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(4)?;
 
@@ -531,7 +1838,7 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::Ns.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
 
                 let position = buffer.position;
@@ -548,7 +1855,7 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::Cname.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
 
                 let position = buffer.position;
@@ -558,6 +1865,35 @@ impl DnsRecord {
                 let size = buffer.position - (position + 2);
                 buffer.set_u16(position, size as u16)?;
             }
+            DnsRecord::Soa {
+                ref domain,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Soa.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+                buffer.write_u16(0)?;
+                buffer.write_qname(m_name)?;
+                buffer.write_qname(r_name)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
             DnsRecord::Mx {
                 ref domain,
                 priority,
@@ -566,7 +1902,7 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::Mx.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
 
                 let position = buffer.position;
@@ -584,7 +1920,7 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::Aaaa.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(16)?;
 
@@ -592,71 +1928,496 @@ impl DnsRecord {
                     buffer.write_u16(*segment)?;
                 }
             }
-            DnsRecord::Unknown { .. } => {
-                println!("Skipping record: {:?}", self)
+            DnsRecord::Sshfp {
+                ref domain,
+                algorithm,
+                fp_type,
+                ref fingerprint,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Sshfp.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(2 + fingerprint.len() as u16)?;
+
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(fp_type)?;
+                buffer.write_bytes(fingerprint)?;
             }
-        }
+            DnsRecord::Uri {
+                ref domain,
+                priority,
+                weight,
+                ref target,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Uri.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
 
-        Ok(buffer.position - start)
-    }
-}
+                let position = buffer.position;
+                buffer.write_u16(0)?;
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_bytes(target.as_bytes())?;
 
-#[derive(Debug, Clone)]
-struct DnsPacket {
-    header: DnsHeader,
-    questions: Vec<DnsQuestion>,
-    answers: Vec<DnsRecord>,
-    authorities: Vec<DnsRecord>,
-    resources: Vec<DnsRecord>,
-}
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            DnsRecord::Nsec {
+                ref domain,
+                ref next_domain,
+                ref type_bitmap,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Nsec.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
 
-impl Default for DnsPacket {
-    fn default() -> Self {
-        Self {
-            header: DnsHeader::new(),
-            questions: Vec::new(),
-            answers: Vec::new(),
-            authorities: Vec::new(),
-            resources: Vec::new(),
-        }
-    }
-}
+                let position = buffer.position;
+                buffer.write_u16(0)?;
+                buffer.write_qname(next_domain)?;
+                buffer.write_bytes(type_bitmap)?;
 
-impl DnsPacket {
-    fn new() -> Self {
-        Self::default()
-    }
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            DnsRecord::Txt {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Txt.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
 
-    fn from_buffer(buffer: &mut BytePacketBuffer) -> anyhow::Result<DnsPacket> {
-        let mut result = DnsPacket::new();
-        result.header.read(buffer)?;
+                let position = buffer.position;
+                buffer.write_u16(0)?;
+                for text in data {
+                    // A character-string holds at most 255 bytes, so longer
+                    // text is split over several of them.
+                    for chunk in text.as_bytes().chunks(255) {
+                        buffer.write_u8(chunk.len() as u8)?;
+                        buffer.write_bytes(chunk)?;
+                    }
+                }
 
-        for _ in 0..result.header.questions {
-            let mut question = DnsQuestion::new("".to_string(), QueryType::Unknown(0));
-            question.read(buffer)?;
-            result.questions.push(question);
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            DnsRecord::Hinfo {
+                ref domain,
+                ref cpu,
+                ref os,
+                ttl,
+            } => {
+                // Unlike TXT data, each field is a single character-string,
+                // so one longer than 255 bytes can't be split up.
+                if let Some(field) = [cpu, os].into_iter().find(|field| field.len() > 255) {
+                    return Err(BytePacketBufferError::CharacterStringTooLong(field.len()).into());
+                }
+
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Hinfo.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(2 + cpu.len() as u16 + os.len() as u16)?;
+
+                for field in [cpu, os] {
+                    buffer.write_u8(field.len() as u8)?;
+                    buffer.write_bytes(field.as_bytes())?;
+                }
+            }
+            DnsRecord::Cds {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Cds.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + digest.len() as u16)?;
+
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                buffer.write_bytes(digest)?;
+            }
+            DnsRecord::Cdnskey {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Cdnskey.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + public_key.len() as u16)?;
+
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_bytes(public_key)?;
+            }
+            DnsRecord::Tsig {
+                ref domain,
+                ref algorithm,
+                time_signed,
+                fudge,
+                ref mac,
+                original_id,
+                error,
+                ref other,
+            } => {
+                // TSIG is always class ANY with a zero TTL.
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Tsig.into())?;
+                buffer.write_u16(QueryClass::Any.into())?;
+                buffer.write_u32(0)?;
+
+                let position = buffer.position;
+                buffer.write_u16(0)?;
+                buffer.write_qname(algorithm)?;
+                buffer.write_u16((time_signed >> 32) as u16)?;
+                buffer.write_u32(time_signed as u32)?;
+                buffer.write_u16(fudge)?;
+                buffer.write_u16(mac.len() as u16)?;
+                buffer.write_bytes(mac)?;
+                buffer.write_u16(original_id)?;
+                buffer.write_u16(error)?;
+                buffer.write_u16(other.len() as u16)?;
+                buffer.write_bytes(other)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            DnsRecord::Dname {
+                ref domain,
+                ref target,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Dname.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+                buffer.write_u16(0)?;
+                buffer.write_qname(target)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            DnsRecord::Cert {
+                ref domain,
+                cert_type,
+                key_tag,
+                algorithm,
+                ref certificate,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::Cert.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(5 + certificate.len() as u16)?;
+
+                buffer.write_u16(cert_type)?;
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_bytes(certificate)?;
+            }
+            DnsRecord::Opt {
+                packet_len,
+                flags,
+                ref options,
+            } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::Opt.into())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+
+                let position = buffer.position;
+                buffer.write_u16(0)?;
+                for option in options {
+                    buffer.write_u16(option.code)?;
+                    buffer.write_u16(option.data.len() as u16)?;
+                    buffer.write_bytes(&option.data)?;
+                }
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            DnsRecord::Unknown {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+                buffer.write_u16(0)?;
+
+                match record_codec(qtype) {
+                    Some(codec) => codec.write(data, buffer)?,
+                    None => buffer.write_bytes(data)?,
+                }
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
         }
 
-        for _ in 0..result.header.answers {
-            result.answers.push(DnsRecord::read(buffer)?);
+        Ok(buffer.position - start)
+    }
+}
+
+/// Converts the rdata of a record type the crate does not know about between
+/// its wire form and the bytes kept in `DnsRecord::Unknown`.
+///
+/// `read` is called with the buffer positioned at the start of the rdata and
+/// may follow compression pointers; the buffer is moved past the rdata
+/// afterwards whatever `read` consumed. Unlike the built-in types, such
+/// records aren't checked against their declared rdata length.
+pub trait RecordCodec: Send + Sync {
+    fn read(&self, buffer: &mut BytePacketBuffer, data_len: u16) -> anyhow::Result<Vec<u8>>;
+    fn write(&self, data: &[u8], buffer: &mut BytePacketBuffer) -> anyhow::Result<()>;
+}
+
+/// Copies the rdata through byte for byte.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpaqueCodec;
+
+impl RecordCodec for OpaqueCodec {
+    fn read(&self, buffer: &mut BytePacketBuffer, data_len: u16) -> anyhow::Result<Vec<u8>> {
+        buffer.read_bytes(data_len as usize)
+    }
+
+    fn write(&self, data: &[u8], buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
+        buffer.write_bytes(data)
+    }
+}
+
+fn record_codecs() -> &'static RwLock<HashMap<u16, Arc<dyn RecordCodec>>> {
+    static CODECS: OnceLock<RwLock<HashMap<u16, Arc<dyn RecordCodec>>>> = OnceLock::new();
+    CODECS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+fn record_codec(qtype: u16) -> Option<Arc<dyn RecordCodec>> {
+    record_codecs().read().unwrap().get(&qtype).cloned()
+}
+
+/// Registers a codec for a record type number, so the rdata of records of
+/// that type in `DnsRecord::Unknown` is read and written by it instead of
+/// being copied as is. Only types without a variant of their own can be
+/// registered, the built-in ones keep their codec in `DnsRecord`.
+pub fn register_record_codec<C: RecordCodec + 'static>(
+    qtype: u16,
+    codec: C,
+) -> Result<(), QueryTypeError> {
+    if !matches!(QueryType::from(qtype), QueryType::Unknown(_)) {
+        return Err(QueryTypeError::AlreadyDefined(qtype));
+    }
+
+    record_codecs()
+        .write()
+        .unwrap()
+        .insert(qtype, Arc::new(codec));
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsPacket {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub resources: Vec<DnsRecord>,
+}
+
+fn same_records(a: &[DnsRecord], b: &[DnsRecord]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let without_ttl = |record: &DnsRecord| {
+        let mut record = record.clone();
+        record.set_ttl(0);
+        record
+    };
+    let mut rest: Vec<DnsRecord> = b.iter().map(without_ttl).collect();
+
+    a.iter().map(without_ttl).all(
+        |record| match rest.iter().position(|other| *other == record) {
+            Some(index) => {
+                rest.swap_remove(index);
+                true
+            }
+            None => false,
+        },
+    )
+}
+
+impl Default for DnsPacket {
+    fn default() -> Self {
+        Self {
+            header: DnsHeader::new(),
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            resources: Vec::new(),
         }
+    }
+}
+
+impl DnsPacket {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_id(mut self, id: u16) -> Self {
+        self.header.id = id;
+        self
+    }
+
+    pub fn with_question(mut self, question: DnsQuestion) -> Self {
+        self.questions.push(question);
+        self.header.questions = self.questions.len() as u16;
+        self
+    }
+
+    pub fn with_recursion_desired(mut self, recursion_desired: bool) -> Self {
+        self.header.recursion_desired = recursion_desired;
+        self
+    }
+
+    /// Compares the question, rcode and each section's records, ignoring
+    /// record order within a section and TTLs.
+    pub fn semantically_eq(&self, other: &DnsPacket) -> bool {
+        self.header.rescode == other.header.rescode
+            && self.questions == other.questions
+            && same_records(&self.answers, &other.answers)
+            && same_records(&self.authorities, &other.authorities)
+            && same_records(&self.resources, &other.resources)
+    }
+
+    /// Empties the packet while keeping the sections' allocations, so it can
+    /// be reused for the next query.
+    pub fn clear(&mut self) {
+        self.header = DnsHeader::new();
+        self.questions.clear();
+        self.answers.clear();
+        self.authorities.clear();
+        self.resources.clear();
+    }
+
+    /// Parses the sections declared in the header. Anything after them, such
+    /// as padding some upstreams append, is left alone.
+    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> anyhow::Result<DnsPacket> {
+        let mut result = DnsPacket::new();
+        result
+            .header
+            .read(buffer)
+            .context("failed parsing header")?;
 
-        for _ in 0..result.header.authoritative_entries {
-            result.authorities.push(DnsRecord::read(buffer)?);
+        for i in 0..result.header.questions {
+            let mut question = DnsQuestion::new("".to_string(), QueryType::Unknown(0));
+            question
+                .read(buffer)
+                .with_context(|| format!("failed parsing question {}", i + 1))?;
+            result.questions.push(question);
         }
 
-        for _ in 0..result.header.resource_entries {
-            result.resources.push(DnsRecord::read(buffer)?);
+        let complete =
+            Self::read_records(buffer, "answer", result.header.answers, &mut result.answers)?
+                && Self::read_records(
+                    buffer,
+                    "authority",
+                    result.header.authoritative_entries,
+                    &mut result.authorities,
+                )?
+                && Self::read_records(
+                    buffer,
+                    "additional",
+                    result.header.resource_entries,
+                    &mut result.resources,
+                )?;
+
+        if !complete {
+            result.header.truncated_message = true;
         }
 
         Ok(result)
     }
 
-    fn write(&mut self, buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
-        self.header.questions = self.questions.len() as u16;
-        self.header.answers = self.answers.len() as u16;
-        self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
+    fn read_records(
+        buffer: &mut BytePacketBuffer,
+        section: &str,
+        count: u16,
+        records: &mut Vec<DnsRecord>,
+    ) -> anyhow::Result<bool> {
+        for i in 0..count {
+            match DnsRecord::read(buffer) {
+                Ok(record) => records.push(record),
+                Err(e) => match e.downcast_ref::<BytePacketBufferError>() {
+                    Some(BytePacketBufferError::EndOfBuffer) => return Ok(false),
+                    _ => {
+                        return Err(e.context(format!(
+                            "failed parsing {} record {}",
+                            section,
+                            i + 1
+                        )))
+                    }
+                },
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn check_section_counts(data: &[u8]) -> anyhow::Result<()> {
+        const HEADER_SIZE: usize = 12;
+        const MIN_QUESTION_SIZE: usize = 5;
+        const MIN_RECORD_SIZE: usize = 11;
+
+        if data.len() < HEADER_SIZE {
+            return Err(DnsPacketError::SectionCountsExceedLength(data.len()).into());
+        }
+
+        let count = |index: usize| u16::from_be_bytes([data[index], data[index + 1]]) as usize;
+        let records = count(6) + count(8) + count(10);
+        let min_len = HEADER_SIZE + count(4) * MIN_QUESTION_SIZE + records * MIN_RECORD_SIZE;
+
+        if min_len > data.len() {
+            return Err(DnsPacketError::SectionCountsExceedLength(data.len()).into());
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
+        let count = |section, len: usize| {
+            u16::try_from(len).map_err(|_| DnsPacketError::SectionTooLarge(section, len))
+        };
+        self.header.questions = count("question", self.questions.len())?;
+        self.header.answers = count("answer", self.answers.len())?;
+        self.header.authoritative_entries = count("authority", self.authorities.len())?;
+        self.header.resource_entries = count("additional", self.resources.len())?;
 
         self.header.write(buffer)?;
 
@@ -664,159 +2425,6461 @@ impl DnsPacket {
             question.write(buffer)?;
         }
 
+        // Records don't carry a class of their own. They are in the class of
+        // the question, which is IN for everything but CHAOS probes.
+        let class = match self.question() {
+            Some(question) if question.qclass == QueryClass::Ch => QueryClass::Ch,
+            _ => QueryClass::In,
+        };
+
         for record in &self.answers {
-            record.write(buffer)?;
+            record.write(buffer, class)?;
         }
 
         for record in &self.authorities {
-            record.write(buffer)?;
+            record.write(buffer, class)?;
         }
 
         for record in &self.resources {
-            record.write(buffer)?;
+            record.write(buffer, class)?;
         }
 
         Ok(())
     }
 
-    fn get_random_a(&self) -> Option<Ipv4Addr> {
-        self.answers.iter().find_map(|record| match record {
-            DnsRecord::A { addr, .. } => Some(*addr),
-            _ => None,
-        })
+    /// Drops records from the end until the packet serializes into
+    /// `max_bytes`: additional records besides OPT first, then authorities,
+    /// then answers. TC is set once answers have to go, so the client retries
+    /// over TCP. Returns whether anything was dropped.
+    pub fn fit_within(&mut self, max_bytes: usize) -> bool {
+        let mut truncated = false;
+
+        loop {
+            let mut buffer = BytePacketBuffer::with_size(max_bytes);
+            let fits = match self.write(&mut buffer) {
+                Ok(()) => true,
+                Err(e) => !matches!(
+                    e.downcast_ref::<BytePacketBufferError>(),
+                    Some(BytePacketBufferError::EndOfBuffer)
+                ),
+            };
+            // Errors other than running out of room aren't fixed by dropping
+            // records, so they are left for the real write to report.
+            if fits {
+                return truncated;
+            }
+
+            let additional = self
+                .resources
+                .iter()
+                .rposition(|record| !matches!(record, DnsRecord::Opt { .. }));
+            if let Some(index) = additional {
+                self.resources.remove(index);
+            } else if self.authorities.pop().is_none() {
+                if self.answers.pop().is_none() {
+                    return truncated;
+                }
+                self.header.truncated_message = true;
+            }
+            truncated = true;
+        }
     }
 
-    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
-        self.authorities
-            .iter()
-            .filter_map(|record| match record {
-                DnsRecord::Ns { domain, host, .. } => Some((domain.as_str(), host.as_str())),
-                _ => None,
-            })
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+    /// Grows the OPT record's padding option, if it has one, so the packet
+    /// serializes to a multiple of `block` bytes (RFC 7830), or to
+    /// `max_bytes` when the next multiple wouldn't fit.
+    pub fn pad_to_block(&mut self, block: usize, max_bytes: usize) {
+        let mut buffer = BytePacketBuffer::with_size(max_bytes);
+        if block == 0 || self.write(&mut buffer).is_err() {
+            return;
+        }
+
+        let padding = self.resources.iter_mut().find_map(|record| match record {
+            DnsRecord::Opt { options, .. } => options
+                .iter_mut()
+                .find(|option| option.code == EDNS_OPTION_PADDING),
+            _ => None,
+        });
+        if let Some(padding) = padding {
+            let unpadded = buffer.position - padding.data.len();
+            let padded = unpadded.div_ceil(block) * block;
+            padding.data = vec![0; padded.min(max_bytes) - unpadded];
+        }
     }
 
-    fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        self.get_ns(qname)
-            .flat_map(|(_, host)| {
-                self.resources
-                    .iter()
-                    .filter_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(addr),
-                        _ => None,
-                    })
-            })
-            .copied()
-            .next()
+    /// Writes the packet into `buffer`, first trimming it with `fit_within`
+    /// to the buffer's size.
+    pub fn write_truncated(&mut self, buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
+        self.fit_within(buffer.buffer.len());
+
+        buffer.seek(0)?;
+        self.write(buffer)
     }
 
-    fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
-        self.get_ns(qname).map(|(_, host)| host).next()
+    pub fn into_response(&self) -> DnsPacket {
+        let mut result = DnsPacket::new();
+        result.header.id = self.header.id;
+        result.header.opcode = self.header.opcode;
+        result.header.recursion_desired = self.header.recursion_desired;
+        result.header.checking_disabled = self.header.checking_disabled;
+        result.header.recursion_available = true;
+        result.header.response = true;
+        result.questions = self.questions.clone();
+        result
     }
-}
 
-fn recursive_lookup(qname: &str, qtype: QueryType) -> anyhow::Result<DnsPacket> {
-    let mut ns = "198.41.0.4".parse::<Ipv4Addr>().unwrap();
+    pub fn clamp_ttls(&mut self, min_ttl: Option<u32>, max_ttl: Option<u32>) {
+        for record in self
+            .answers
+            .iter_mut()
+            .chain(self.authorities.iter_mut())
+            .chain(self.resources.iter_mut())
+        {
+            let mut ttl = record.ttl();
+            if let Some(min_ttl) = min_ttl {
+                ttl = ttl.max(min_ttl);
+            }
+            if let Some(max_ttl) = max_ttl {
+                ttl = ttl.min(max_ttl);
+            }
+            record.set_ttl(ttl);
+        }
+    }
 
-    loop {
-        println!("Attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+    pub fn opt(&self) -> Option<&DnsRecord> {
+        self.resources
+            .iter()
+            .find(|record| matches!(record, DnsRecord::Opt { .. }))
+    }
 
-        let ns_copy = ns;
+    /// The client subnet from an EDNS Client Subnet option, if there is one.
+    pub fn client_subnet(&self) -> Option<Subnet> {
+        let options = match self.opt()? {
+            DnsRecord::Opt { options, .. } => options,
+            _ => return None,
+        };
+        let data = &options
+            .iter()
+            .find(|option| option.code == EDNS_OPTION_CLIENT_SUBNET)?
+            .data;
+        if data.len() < 4 {
+            return None;
+        }
 
-        let server = (ns_copy, 53);
-        let response = lookup(qname, qtype, server)?;
+        // Only the first source-prefix-length bits of the address are sent.
+        let prefix_len = data[2];
+        let bytes = &data[4..];
+        let addr = match u16::from_be_bytes([data[0], data[1]]) {
+            1 if bytes.len() <= 4 && prefix_len <= 32 => {
+                let mut octets = [0; 4];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::from(octets)
+            }
+            2 if bytes.len() <= 16 && prefix_len <= 128 => {
+                let mut octets = [0; 16];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::from(octets)
+            }
+            _ => return None,
+        };
 
-        if !response.answers.is_empty() && response.header.rescode == ResultCode::NoError {
-            return Ok(response);
-        }
+        Some(Subnet { addr, prefix_len })
+    }
 
-        if response.header.rescode == ResultCode::NxDomain {
-            return Ok(response);
+    pub fn add_opt_for(&mut self, request: &DnsPacket) {
+        if self.opt().is_some() {
+            return;
         }
 
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns;
-            continue;
+        if let Some(DnsRecord::Opt {
+            packet_len, flags, ..
+        }) = request.opt()
+        {
+            self.resources.push(DnsRecord::Opt {
+                packet_len: (*packet_len).min(MAX_BUFFER_SIZE as u16),
+                flags: flags & EDNS_FLAG_DNSSEC_OK,
+                options: Vec::new(),
+            });
         }
+    }
 
-        let new_ns = match response.get_unresolved_ns(qname) {
-            Some(ns) => ns,
-            None => return Ok(response),
-        };
-        let recursive_response = recursive_lookup(new_ns, QueryType::A)?;
+    pub fn add_extended_error(&mut self, info_code: u16, extra_text: &str) {
+        self.add_edns_option(EdnsOption::extended_error(info_code, extra_text));
+    }
 
-        if let Some(new_ns) = recursive_response.get_random_a() {
-            ns = new_ns;
-        } else {
-            return Ok(response);
+    pub fn has_edns_option(&self, code: u16) -> bool {
+        match self.opt() {
+            Some(DnsRecord::Opt { options, .. }) => {
+                options.iter().any(|option| option.code == code)
+            }
+            _ => false,
         }
     }
-}
 
-fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> anyhow::Result<DnsPacket> {
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+    /// Appends `option` to the OPT record, adding one if there is none yet.
+    pub fn add_edns_option(&mut self, option: EdnsOption) {
+        for record in &mut self.resources {
+            if let DnsRecord::Opt { options, .. } = record {
+                options.push(option);
+                return;
+            }
+        }
 
-    let mut req_packet = DnsPacket::new();
-    req_packet.header.id = 6666;
-    req_packet.header.questions = 1;
-    req_packet.header.recursion_desired = true;
-    req_packet
-        .questions
-        .push(DnsQuestion::new(qname.to_string(), qtype));
+        self.resources.push(DnsRecord::Opt {
+            packet_len: MAX_BUFFER_SIZE as u16,
+            flags: 0,
+            options: vec![option],
+        });
+    }
 
-    let mut req_buffer = BytePacketBuffer::new();
-    req_packet.write(&mut req_buffer)?;
-    socket.send_to(&req_buffer.buffer[0..req_buffer.position], server)?;
+    pub fn nxdomain_for(question: DnsQuestion, soa: DnsRecord) -> DnsPacket {
+        let mut result = DnsPacket::new();
+        result.header.response = true;
+        result.header.authoritative_answer = true;
+        result.header.rescode = ResultCode::NxDomain;
+        result.questions.push(question);
+        result.authorities.push(soa);
+        result
+    }
 
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buffer)?;
+    /// Adds the CNAME that a DNAME in the answers implies for `qname` (RFC
+    /// 6672), unless the server already sent it, for clients that only know
+    /// how to follow CNAMEs.
+    fn synthesize_dname_cname(&mut self, qname: &str) {
+        let has_cname = self.answers.iter().any(|record| {
+            matches!(record, DnsRecord::Cname { domain, .. } if domain.eq_ignore_ascii_case(qname))
+        });
+        if has_cname {
+            return;
+        }
 
-    DnsPacket::from_buffer(&mut res_buffer)
-}
+        let lowercase = qname.to_lowercase();
+        let synthesized = self.answers.iter().enumerate().find_map(|(i, record)| {
+            let (domain, target, ttl) = match record {
+                DnsRecord::Dname {
+                    domain,
+                    target,
+                    ttl,
+                } => (domain, target, *ttl),
+                _ => return None,
+            };
+            let suffix = format!(".{}", domain.to_lowercase());
+            if !lowercase.ends_with(&suffix) {
+                return None;
+            }
 
-pub fn handle_query(socket: &UdpSocket) -> anyhow::Result<()> {
-    let mut req_buffer = BytePacketBuffer::new();
-    let (_, src) = socket.recv_from(&mut req_buffer.buffer)?;
-    let mut req_packet = DnsPacket::from_buffer(&mut req_buffer)?;
+            let host = format!("{}.{}", &qname[..qname.len() - suffix.len()], target);
+            // A substitution that makes the name too long can't be followed.
+            if host.len() > 253 {
+                return None;
+            }
 
-    let mut res_packet = DnsPacket::new();
-    res_packet.header.id = req_packet.header.id;
-    res_packet.header.recursion_desired = true;
-    res_packet.header.recursion_available = true;
-    res_packet.header.response = true;
+            let cname = DnsRecord::Cname {
+                domain: qname.to_string(),
+                host,
+                ttl,
+            };
+            Some((i + 1, cname))
+        });
 
-    if let Some(question) = req_packet.questions.pop() {
-        println!("Received query: {:?}", question);
+        if let Some((index, cname)) = synthesized {
+            self.answers.insert(index, cname);
+        }
+    }
 
-        if let Ok(result) = recursive_lookup(&question.name, question.qtype) {
-            res_packet.questions.push(question);
-            res_packet.header.rescode = result.header.rescode;
+    fn unresolved_cname(&self, qname: &str, qtype: QueryType) -> Option<String> {
+        if qtype == QueryType::Cname || self.header.rescode != ResultCode::NoError {
+            return None;
+        }
 
-            for record in result.answers {
-                println!("Answer: {:?}", record);
-                res_packet.answers.push(record);
-            }
+        let mut name = qname.to_string();
+        for _ in 0..=self.answers.len() {
+            let question = DnsQuestion::new(name.clone(), qtype);
+            let mut cname = None;
 
-            for record in result.authorities {
-                println!("Authority: {:?}", record);
-                res_packet.authorities.push(record);
+            for record in &self.answers {
+                match record {
+                    DnsRecord::Cname { domain, host, .. } if domain.eq_ignore_ascii_case(&name) => {
+                        cname = Some(host.clone());
+                    }
+                    _ if question.matches(record) => return None,
+                    _ => {}
+                }
             }
 
-            for record in result.resources {
-                println!("Resource: {:?}", record);
-                res_packet.resources.push(record);
+            match cname {
+                Some(host) => name = host,
+                None => break,
             }
+        }
+
+        if name.eq_ignore_ascii_case(qname) {
+            None
         } else {
-            res_packet.header.rescode = ResultCode::ServFail;
+            Some(name)
         }
-    } else {
-        res_packet.header.rescode = ResultCode::FormErr;
     }
 
-    let mut res_buffer = BytePacketBuffer::new();
-    res_packet.write(&mut res_buffer)?;
-    let data = res_buffer.get_range(0, res_buffer.position)?;
-    socket.send_to(data, src)?;
+    pub fn question(&self) -> Option<&DnsQuestion> {
+        self.questions.first()
+    }
 
-    Ok(())
+    pub fn to_dig_string(&self) -> String {
+        let header = &self.header;
+        let opcode = match header.opcode {
+            0 => "QUERY".to_string(),
+            1 => "IQUERY".to_string(),
+            2 => "STATUS".to_string(),
+            4 => "NOTIFY".to_string(),
+            5 => "UPDATE".to_string(),
+            num => num.to_string(),
+        };
+
+        let mut out = format!(
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}\n",
+            opcode, header.rescode, header.id
+        );
+        out.push_str(&format!(
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}\n",
+            header.flags_string(),
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.resources.len()
+        ));
+
+        for record in &self.resources {
+            if let DnsRecord::Opt { .. } = record {
+                out.push_str(&format!("\n;; OPT PSEUDOSECTION:\n{}\n", record));
+            }
+        }
+
+        if !self.questions.is_empty() {
+            out.push_str("\n;; QUESTION SECTION:\n");
+            for question in &self.questions {
+                out.push_str(&format!("{}\n", question));
+            }
+        }
+
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.resources),
+        ];
+        for (name, records) in sections {
+            let records = records
+                .iter()
+                .filter(|record| !matches!(record, DnsRecord::Opt { .. }))
+                .collect::<Vec<_>>();
+            if records.is_empty() {
+                continue;
+            }
+
+            out.push_str(&format!("\n;; {} SECTION:\n", name));
+            for record in records {
+                out.push_str(&format!("{}\n", record));
+            }
+        }
+
+        out
+    }
+
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                DnsRecord::Aaaa { addr, .. } => Some(IpAddr::V6(*addr)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// How long a negative answer may be cached: the SOA minimum from the
+    /// authority section, capped by the SOA's own TTL (RFC 2308).
+    pub fn negative_ttl(&self) -> Option<u32> {
+        self.authorities.iter().find_map(|record| match record {
+            DnsRecord::Soa { minimum, ttl, .. } => Some((*minimum).min(*ttl)),
+            _ => None,
+        })
+    }
+
+    pub fn preferred_address(&self, prefer_v6: bool) -> Option<IpAddr> {
+        let addrs = self.addresses();
+        addrs
+            .iter()
+            .find(|addr| addr.is_ipv6() == prefer_v6)
+            .or_else(|| addrs.first())
+            .copied()
+    }
+
+    /// Moves the address answers of the preferred family ahead of the other
+    /// family, keeping the relative order of everything else.
+    pub fn prefer_family(&mut self, prefer_v6: bool) {
+        self.answers.sort_by_key(|record| match record {
+            DnsRecord::A { .. } => prefer_v6,
+            DnsRecord::Aaaa { .. } => !prefer_v6,
+            _ => false,
+        });
+    }
+
+    fn get_random_a(&self) -> Option<Ipv4Addr> {
+        self.answers.iter().find_map(|record| match record {
+            DnsRecord::A { addr, .. } => Some(*addr),
+            _ => None,
+        })
+    }
+
+    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.authorities
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::Ns { domain, host, .. } => Some((domain.as_str(), host.as_str())),
+                _ => None,
+            })
+            .filter(move |(domain, _)| {
+                is_in_bailiwick(&qname.to_ascii_lowercase(), &domain.to_ascii_lowercase())
+            })
+    }
+
+    fn get_resolved_ns(
+        &self,
+        qname: &str,
+        selection: NsSelection,
+        rtts: &RttTracker,
+    ) -> Option<Ipv4Addr> {
+        let addrs = self.get_resolved_ns_all(qname);
+
+        match selection {
+            NsSelection::First => addrs.first().copied(),
+            NsSelection::Random if !addrs.is_empty() => {
+                Some(addrs[random_u64() as usize % addrs.len()])
+            }
+            NsSelection::Random => None,
+            // Servers that have never been measured sort first, so each of
+            // them gets tried before settling on the fastest one.
+            NsSelection::Fastest => addrs
+                .iter()
+                .min_by_key(|addr| rtts.get(**addr).unwrap_or_default())
+                .copied(),
+        }
+    }
+
+    /// The addresses the additional section gives for nameserver `ns_host`.
+    pub fn glue_for(&self, ns_host: &str) -> Vec<IpAddr> {
+        self.resources
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { domain, addr, .. } if domain.eq_ignore_ascii_case(ns_host) => {
+                    Some(IpAddr::V4(*addr))
+                }
+                DnsRecord::Aaaa { domain, addr, .. } if domain.eq_ignore_ascii_case(ns_host) => {
+                    Some(IpAddr::V6(*addr))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn get_resolved_ns_all(&self, qname: &str) -> Vec<Ipv4Addr> {
+        self.get_ns(qname)
+            .flat_map(|(_, host)| self.glue_for(host))
+            .filter_map(|addr| match addr {
+                IpAddr::V4(addr) => Some(addr),
+                IpAddr::V6(_) => None,
+            })
+            .collect()
+    }
+
+    fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+        self.get_ns(qname).map(|(_, host)| host).next()
+    }
+
+    fn get_unresolved_ns_all<'a>(&'a self, qname: &'a str) -> Vec<&'a str> {
+        self.get_ns(qname).map(|(_, host)| host).collect()
+    }
+
+    fn get_delegation_zone<'a>(&'a self, qname: &'a str) -> &'a str {
+        self.get_ns(qname)
+            .map(|(domain, _)| domain)
+            .next()
+            .unwrap_or("")
+    }
+
+    fn retain_in_bailiwick(&mut self, zone: &str) {
+        let zone = zone.to_ascii_lowercase();
+        let in_bailiwick = |record: &DnsRecord| {
+            let kept = is_in_bailiwick(&record.domain().to_ascii_lowercase(), &zone);
+            if !kept {
+                log!("Dropping out-of-bailiwick record: {:?}", record);
+            }
+            kept
+        };
+
+        self.answers.retain(in_bailiwick);
+        self.authorities.retain(in_bailiwick);
+        self.resources.retain(in_bailiwick);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NsSelection {
+    #[default]
+    First,
+    Random,
+    Fastest,
+}
+
+/// Smoothed round-trip times of upstream nameservers, keyed by address.
+#[derive(Debug, Default)]
+pub struct RttTracker {
+    rtts: Mutex<HashMap<Ipv4Addr, Duration>>,
+}
+
+impl RttTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Folds a new sample into the server's estimate, weighting the previous
+    /// estimate by 7/8 so a single slow reply doesn't dominate.
+    pub fn record(&self, server: Ipv4Addr, rtt: Duration) {
+        let mut rtts = self.rtts.lock().unwrap();
+        let srtt = match rtts.get(&server) {
+            Some(previous) => (*previous * 7 + rtt) / 8,
+            None => rtt,
+        };
+        rtts.insert(server, srtt);
+    }
+
+    pub fn get(&self, server: Ipv4Addr) -> Option<Duration> {
+        self.rtts.lock().unwrap().get(&server).copied()
+    }
+
+    pub fn snapshot(&self) -> HashMap<Ipv4Addr, Duration> {
+        self.rtts.lock().unwrap().clone()
+    }
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn is_in_bailiwick(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// Follows a single query through resolution, recording which servers were
+/// asked and which delegations were chased on the way.
+#[derive(Debug)]
+pub struct QueryContext {
+    pub id: u16,
+    pub question: DnsQuestion,
+    /// Resolution gives up once this has passed.
+    pub deadline: Option<Instant>,
+    servers: Mutex<Vec<Ipv4Addr>>,
+    delegations: Mutex<Vec<String>>,
+}
+
+impl QueryContext {
+    pub fn new(id: u16, question: DnsQuestion) -> Self {
+        Self {
+            id,
+            question,
+            deadline: None,
+            servers: Mutex::new(Vec::new()),
+            delegations: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Every server queried so far, in order.
+    pub fn servers(&self) -> Vec<Ipv4Addr> {
+        self.servers.lock().unwrap().clone()
+    }
+
+    /// The zones referrals pointed at so far, in order.
+    pub fn delegations(&self) -> Vec<String> {
+        self.delegations.lock().unwrap().clone()
+    }
+
+    fn contacted(&self, server: Ipv4Addr) {
+        self.servers.lock().unwrap().push(server);
+    }
+
+    fn delegated(&self, zone: &str) {
+        self.delegations.lock().unwrap().push(zone.to_string());
+    }
+
+    fn check_deadline(&self) -> anyhow::Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                anyhow::bail!("Gave up resolving {} at its deadline", self.question.name)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+struct LookupFrame {
+    qname: String,
+    qtype: QueryType,
+    ns: Ipv4Addr,
+    // Other addresses of the current zone's nameservers, tried in order when
+    // `ns` doesn't answer.
+    fallbacks: Vec<Ipv4Addr>,
+    zone: String,
+    referral: Option<DnsPacket>,
+}
+
+impl LookupFrame {
+    fn new(qname: &str, qtype: QueryType, cache: &dyn CacheStore, roots: &[Ipv4Addr]) -> Self {
+        let (zone, ns, fallbacks) = match closest_cached_ns(cache, qname) {
+            Some((zone, ns)) => (zone, ns, Vec::new()),
+            None => match roots.split_first() {
+                Some((ns, fallbacks)) => (String::new(), *ns, fallbacks.to_vec()),
+                None => (String::new(), Ipv4Addr::UNSPECIFIED, Vec::new()),
+            },
+        };
+
+        Self {
+            qname: qname.to_string(),
+            qtype,
+            ns,
+            fallbacks,
+            zone,
+            referral: None,
+        }
+    }
+}
+
+fn closest_cached_ns(cache: &dyn CacheStore, qname: &str) -> Option<(String, Ipv4Addr)> {
+    let mut zone = qname;
+
+    loop {
+        if let Some(delegation) = cache.get_delegation(zone) {
+            let addr = delegation.answers.iter().find_map(|record| match record {
+                DnsRecord::Ns { host, .. } => delegation
+                    .glue_for(host)
+                    .into_iter()
+                    .find_map(|addr| match addr {
+                        IpAddr::V4(addr) => Some(addr),
+                        IpAddr::V6(_) => None,
+                    })
+                    .or_else(|| {
+                        cache
+                            .get(host, QueryType::A)
+                            .and_then(|answer| answer.get_random_a())
+                    }),
+                _ => None,
+            });
+
+            if let Some(addr) = addr {
+                log!("Using cached delegation for {} via {}", zone, addr);
+                return Some((zone.to_string(), addr));
+            }
+        }
+
+        zone = zone.split_once('.')?.1;
+    }
+}
+
+fn cache_referral(cache: &dyn CacheStore, response: &DnsPacket, qname: &str) {
+    let qname = qname.to_lowercase();
+    let mut delegations: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+
+    for record in &response.authorities {
+        if let DnsRecord::Ns { domain, .. } = record {
+            let zone = domain.to_lowercase();
+            if is_in_bailiwick(&qname, &zone) {
+                delegations.entry(zone).or_default().push(record.clone());
+            }
+        }
+    }
+
+    for (zone, records) in delegations {
+        let is_glue = |record: &&DnsRecord| match record {
+            DnsRecord::A { domain, .. } => records.iter().any(
+                |ns| matches!(ns, DnsRecord::Ns { host, .. } if host.eq_ignore_ascii_case(domain)),
+            ),
+            _ => false,
+        };
+
+        // Neither the NS records nor the glue are the zone's own answer, so
+        // they're kept out of the answer cache and only used to pick where
+        // the next lookup starts.
+        let mut delegation = DnsPacket::new();
+        delegation.resources = response.resources.iter().filter(is_glue).cloned().collect();
+        delegation.answers = records;
+
+        let ttl = delegation
+            .answers
+            .iter()
+            .chain(delegation.resources.iter())
+            .map(DnsRecord::ttl)
+            .min()
+            .unwrap_or(0);
+        cache.put_delegation(&zone, delegation, ttl);
+    }
+}
+
+const MAX_CNAME_HOPS: usize = 8;
+
+fn recursive_lookup(
+    qname: &str,
+    qtype: QueryType,
+    config: &ResolverConfig,
+    rtts: &RttTracker,
+    cache: &dyn CacheStore,
+    query: &QueryContext,
+) -> anyhow::Result<DnsPacket> {
+    let mut response = iterative_lookup(qname, qtype, config, rtts, cache, query)?;
+    let mut chain = Vec::new();
+    let mut name = qname.to_string();
+    let mut visited = HashSet::from([name.to_lowercase()]);
+
+    for _ in 0..MAX_CNAME_HOPS {
+        response.synthesize_dname_cname(&name);
+        let target = match response.unresolved_cname(&name, qtype) {
+            Some(target) => target,
+            None => break,
+        };
+        if !visited.insert(target.to_lowercase()) {
+            anyhow::bail!("CNAME loop at {}", target);
+        }
+
+        log!("Following CNAME to {}", target);
+        let next = iterative_lookup(&target, qtype, config, rtts, cache, query)?;
+        chain.append(&mut response.answers);
+        response = next;
+        name = target;
+    }
+
+    chain.append(&mut response.answers);
+    response.answers = chain;
+    Ok(response)
+}
+
+fn iterative_lookup(
+    qname: &str,
+    qtype: QueryType,
+    config: &ResolverConfig,
+    rtts: &RttTracker,
+    cache: &dyn CacheStore,
+    query: &QueryContext,
+) -> anyhow::Result<DnsPacket> {
+    let mut stack = vec![LookupFrame::new(qname, qtype, cache, &config.root_servers)];
+
+    loop {
+        query.check_deadline()?;
+
+        let frame = stack.last_mut().unwrap();
+        log!(
+            "Attempting lookup of {:?} {} with ns {}",
+            frame.qtype,
+            frame.qname,
+            frame.ns
+        );
+
+        query.contacted(frame.ns);
+        let server = (frame.ns, config.upstream_port);
+        let started = Instant::now();
+        let response = lookup_once(&frame.qname, frame.qtype, server, false, config);
+        // A server that doesn't answer is charged the full timeout.
+        let rtt = match response {
+            Ok(_) => started.elapsed(),
+            Err(_) => config.timeout,
+        };
+        rtts.record(frame.ns, rtt);
+
+        let mut response = match response {
+            Ok(response) => response,
+            Err(e) if !frame.fallbacks.is_empty() => {
+                log!(
+                    "No answer from {} ({:#}), trying the next address",
+                    frame.ns,
+                    e
+                );
+                frame.ns = frame.fallbacks.remove(0);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        response.retain_in_bailiwick(&frame.zone);
+        if response.answers.is_empty() {
+            cache_referral(cache, &response, &frame.qname);
+        }
+
+        let unresolved: Vec<String> = response
+            .get_unresolved_ns_all(&frame.qname)
+            .into_iter()
+            .take(config.ns_concurrency)
+            .map(str::to_string)
+            .collect();
+
+        let resolved = if (!response.answers.is_empty()
+            && response.header.rescode == ResultCode::NoError)
+            || response.header.rescode == ResultCode::NxDomain
+        {
+            Some(response)
+        } else if let Some(new_ns) =
+            response.get_resolved_ns(&frame.qname, config.ns_selection, rtts)
+        {
+            frame.ns = new_ns;
+            frame.fallbacks = response
+                .get_resolved_ns_all(&frame.qname)
+                .into_iter()
+                .filter(|addr| *addr != new_ns)
+                .collect();
+            frame.zone = response.get_delegation_zone(&frame.qname).to_string();
+            query.delegated(&frame.zone);
+            None
+        } else if unresolved.len() > 1 {
+            match resolve_ns_concurrently(unresolved, config, rtts, cache, query) {
+                Some(new_ns) => {
+                    frame.ns = new_ns;
+                    frame.fallbacks.clear();
+                    frame.zone = response.get_delegation_zone(&frame.qname).to_string();
+                    query.delegated(&frame.zone);
+                    None
+                }
+                None => Some(response),
+            }
+        } else if let Some(new_ns) = response.get_unresolved_ns(&frame.qname) {
+            let next = LookupFrame::new(new_ns, QueryType::A, cache, &config.root_servers);
+            // Nameservers that can only be found through each other's zones
+            // would otherwise be chased forever, so the referral is given up
+            // on like one without any nameserver.
+            if stack
+                .iter()
+                .any(|pending| pending.qname == next.qname && pending.qtype == next.qtype)
+            {
+                log!("Nameserver lookup for {} is already pending", next.qname);
+                Some(response)
+            } else {
+                stack.last_mut().unwrap().referral = Some(response);
+                stack.push(next);
+                None
+            }
+        } else {
+            Some(response)
+        };
+
+        let mut result = match resolved {
+            Some(result) => result,
+            None => continue,
+        };
+
+        // Hand the finished lookup back to the frame that was waiting on a
+        // nameserver address, unwinding as long as no address is found.
+        loop {
+            stack.pop();
+
+            let parent = match stack.last_mut() {
+                Some(parent) => parent,
+                None => return Ok(result),
+            };
+            let referral = parent.referral.take().unwrap();
+
+            if let Some(new_ns) = result.get_random_a() {
+                parent.ns = new_ns;
+                parent.fallbacks = result
+                    .addresses()
+                    .into_iter()
+                    .filter_map(|addr| match addr {
+                        IpAddr::V4(addr) if addr != new_ns => Some(addr),
+                        _ => None,
+                    })
+                    .collect();
+                parent.zone = referral.get_delegation_zone(&parent.qname).to_string();
+                query.delegated(&parent.zone);
+                break;
+            }
+
+            result = referral;
+        }
+    }
+}
+
+/// Looks up the addresses of several nameservers at once and returns the
+/// first one found. The lookups share the caller's cache, round-trip times
+/// and query context, and every address found is cached, so the slower ones
+/// still pay off for later queries. This returns once all of them are done.
+fn resolve_ns_concurrently(
+    hosts: Vec<String>,
+    config: &ResolverConfig,
+    rtts: &RttTracker,
+    cache: &dyn CacheStore,
+    query: &QueryContext,
+) -> Option<Ipv4Addr> {
+    let (sender, receiver) = mpsc::channel();
+    let config = &ResolverConfig {
+        ns_concurrency: 1,
+        ..config.clone()
+    };
+
+    let tag = QUERY_TAG.with(|tag| tag.borrow().clone());
+
+    thread::scope(|scope| {
+        for host in hosts {
+            let sender = sender.clone();
+            let tag = tag.clone();
+            scope.spawn(move || {
+                let _tag = tag.map(QueryTagGuard::set);
+                log!("Resolving nameserver {} concurrently", host);
+                let result = recursive_lookup(&host, QueryType::A, config, rtts, cache, query);
+                let addr = match result {
+                    Ok(packet) => {
+                        let addr = packet.get_random_a();
+                        if let Some(ttl) = cache_ttl(&packet) {
+                            cache.put(&host, QueryType::A, packet, ttl);
+                        }
+                        addr
+                    }
+                    Err(_) => None,
+                };
+                let _ = sender.send(addr);
+            });
+        }
+        drop(sender);
+
+        receiver.iter().flatten().next()
+    })
+}
+
+/// Resolves `qname` from the root, following CNAMEs, and returns the records
+/// along the chain in order, ending with those of the final name.
+pub fn resolve_chain(qname: &str, qtype: QueryType) -> anyhow::Result<Vec<DnsRecord>> {
+    let query = QueryContext::new(0, DnsQuestion::new(qname.to_string(), qtype));
+    let response = lookup(&query, &ResolverConfig::default())?;
+
+    Ok(response.answers)
+}
+
+/// Resolves the question of `query` from the root with a private cache,
+/// recording the servers and delegations involved in `query`.
+pub fn lookup(query: &QueryContext, config: &ResolverConfig) -> anyhow::Result<DnsPacket> {
+    recursive_lookup(
+        &query.question.name,
+        query.question.qtype,
+        config,
+        &RttTracker::new(),
+        &Cache::with_capacity(config.cache_size),
+        query,
+    )
+}
+
+/// Resolves `host` to its IPv4 and IPv6 addresses paired with `port`, much
+/// like `ToSocketAddrs` does through the system resolver. An address literal
+/// is returned as it is.
+pub fn resolve_socket_addrs(host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    resolve_socket_addrs_with(host, port, &ResolverConfig::default())
+}
+
+/// Like `resolve_socket_addrs`, resolving through `config`.
+pub fn resolve_socket_addrs_with(
+    host: &str,
+    port: u16,
+    config: &ResolverConfig,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(addr, port)]);
+    }
+
+    let name = normalize_name(host)?;
+    let rtts = RttTracker::new();
+    let cache = Cache::with_capacity(config.cache_size);
+
+    let mut addrs = Vec::new();
+    let mut error = None;
+    for qtype in [QueryType::A, QueryType::Aaaa] {
+        let query = QueryContext::new(0, DnsQuestion::new(name.clone(), qtype));
+        match recursive_lookup(&name, qtype, config, &rtts, &cache, &query) {
+            Ok(response) => addrs.extend(
+                response
+                    .addresses()
+                    .into_iter()
+                    .map(|addr| SocketAddr::new(addr, port)),
+            ),
+            Err(e) => error = Some(e),
+        }
+    }
+
+    // Only fail when neither family could be looked up.
+    match error {
+        Some(e) if addrs.is_empty() => Err(e),
+        _ => Ok(addrs),
+    }
+}
+
+/// Client side of EDNS cookies (RFC 7873). Client cookies are derived from a
+/// per-process secret and the server's address, and the server cookies sent
+/// back are kept so later queries to the same server can echo them.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    secret: RandomState,
+    server_cookies: Mutex<HashMap<Ipv4Addr, Vec<u8>>>,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn client_cookie(&self, server: Ipv4Addr) -> [u8; 8] {
+        let mut hasher = self.secret.build_hasher();
+        hasher.write(&server.octets());
+        hasher.finish().to_be_bytes()
+    }
+
+    pub fn server_cookie(&self, server: Ipv4Addr) -> Option<Vec<u8>> {
+        self.server_cookies.lock().unwrap().get(&server).cloned()
+    }
+
+    /// The COOKIE option to attach to a query for `server`.
+    pub fn option_for(&self, server: Ipv4Addr) -> EdnsOption {
+        let mut data = self.client_cookie(server).to_vec();
+        if let Some(cookie) = self.server_cookie(server) {
+            data.extend_from_slice(&cookie);
+        }
+
+        EdnsOption {
+            code: EDNS_OPTION_COOKIE,
+            data,
+        }
+    }
+
+    /// Checks the COOKIE option of a response from `server`, if it has one,
+    /// and remembers the server cookie in it. A response echoing the wrong
+    /// client cookie wasn't sent in reply to our query.
+    pub fn accept(&self, server: Ipv4Addr, response: &DnsPacket) -> anyhow::Result<()> {
+        let cookie = match response.opt() {
+            Some(DnsRecord::Opt { options, .. }) => options
+                .iter()
+                .find(|option| option.code == EDNS_OPTION_COOKIE),
+            _ => None,
+        };
+        let cookie = match cookie {
+            Some(option) => &option.data,
+            None => return Ok(()),
+        };
+
+        if cookie.len() < 8 || cookie[..8] != self.client_cookie(server) {
+            return Err(TransportError::ClientCookieMismatch(server).into());
+        }
+
+        // Server cookies are 8 to 32 bytes long, anything else is ignored.
+        if (16..=40).contains(&cookie.len()) {
+            self.server_cookies
+                .lock()
+                .unwrap()
+                .insert(server, cookie[8..].to_vec());
+        }
+
+        Ok(())
+    }
+}
+
+/// The cookie jar shared by every upstream query.
+pub fn cookie_jar() -> &'static CookieJar {
+    static COOKIES: OnceLock<CookieJar> = OnceLock::new();
+    COOKIES.get_or_init(CookieJar::new)
+}
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_RESOLUTION_BUDGET: Duration = Duration::from_secs(10);
+
+/// How lookups against upstream servers are carried out.
+#[derive(Debug, Clone)]
+pub struct ResolverConfig {
+    /// How long to wait for each response.
+    pub timeout: Duration,
+    /// How many more times a query that got no response is sent to the same
+    /// server before giving up on it.
+    pub retries: usize,
+    /// Where resolution starts when no delegation is cached. The first one is
+    /// asked, the others are fallbacks when it doesn't answer.
+    pub root_servers: Vec<Ipv4Addr>,
+    /// The port nameservers are queried on.
+    pub upstream_port: u16,
+    /// The UDP payload size advertised in queries, and so the largest
+    /// response accepted.
+    pub edns_payload_size: u16,
+    pub ns_selection: NsSelection,
+    /// How many nameservers without glue are looked up at once.
+    pub ns_concurrency: usize,
+    /// The address queries are sent from, any address when unspecified.
+    pub source: Ipv4Addr,
+    /// Sends each query with a random ID and random name case (0x20), and
+    /// discards responses that don't come from the server's address and port
+    /// or don't echo both back exactly.
+    pub spoofing_protection: bool,
+    /// How many entries the cache of lookups made with this configuration
+    /// holds.
+    pub cache_size: usize,
+    /// How long resolving a client's query may take before it's given up.
+    pub budget: Duration,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            timeout: UPSTREAM_TIMEOUT,
+            retries: 0,
+            // a, c and d.root-servers.net.
+            root_servers: vec![
+                Ipv4Addr::new(198, 41, 0, 4),
+                Ipv4Addr::new(192, 33, 4, 12),
+                Ipv4Addr::new(199, 7, 91, 13),
+            ],
+            upstream_port: 53,
+            edns_payload_size: MAX_BUFFER_SIZE as u16,
+            ns_selection: NsSelection::default(),
+            ns_concurrency: 1,
+            source: Ipv4Addr::UNSPECIFIED,
+            spoofing_protection: false,
+            cache_size: DEFAULT_CACHE_SIZE,
+            budget: DEFAULT_RESOLUTION_BUDGET,
+        }
+    }
+}
+
+/// Flips the case of each letter of `name` at random, which a forged
+/// response has to guess along with the ID.
+fn randomize_case(name: &str) -> String {
+    let mut bits = 0;
+
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if i % 64 == 0 {
+                bits = random_u64();
+            }
+            let upper = (bits >> (i % 64)) & 1 == 1;
+
+            if upper {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+fn check_response(
+    res_buffer: &BytePacketBuffer,
+    len: usize,
+    from: SocketAddr,
+    server: (Ipv4Addr, u16),
+    req_packet: &DnsPacket,
+) -> anyhow::Result<()> {
+    if from != SocketAddr::from(server) {
+        return Err(TransportError::MismatchedResponse("source address").into());
+    }
+
+    // Read the echoed question again without folding its case.
+    let mut echoed = BytePacketBuffer::from_bytes(&res_buffer.buffer[..len])?;
+    echoed.lowercase_names = false;
+    let mut header = DnsHeader::new();
+    header.read(&mut echoed)?;
+    if header.id != req_packet.header.id {
+        return Err(TransportError::MismatchedResponse("ID").into());
+    }
+
+    let mut question = DnsQuestion::new(String::new(), QueryType::Unknown(0));
+    if header.questions == 0 || question.read(&mut echoed).is_err() {
+        return Err(TransportError::MismatchedResponse("question").into());
+    }
+    if req_packet.question() != Some(&question) {
+        return Err(TransportError::MismatchedResponse("question").into());
+    }
+
+    Ok(())
+}
+
+/// Waits up to the configured timeout for the response to `req_packet` and
+/// returns its length. With spoofing protection on, datagrams that don't
+/// match the query are logged and dropped rather than ending the wait, so a
+/// forged packet can't cut the lookup short.
+fn receive_response(
+    socket: &UdpSocket,
+    res_buffer: &mut BytePacketBuffer,
+    server: (Ipv4Addr, u16),
+    req_packet: &DnsPacket,
+    config: &ResolverConfig,
+) -> io::Result<usize> {
+    let deadline = Instant::now() + config.timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (len, from) = socket.recv_from(&mut res_buffer.buffer)?;
+        if config.spoofing_protection {
+            if let Err(e) = check_response(res_buffer, len, from, server, req_packet) {
+                log!("{} from {}", e, from);
+                continue;
+            }
+        }
+
+        return Ok(len);
+    }
+}
+
+/// Sends a single query to `server` and returns its response as-is, without
+/// following referrals or resolving CNAMEs.
+///
+/// `recursion_desired` should only be set when `server` is a recursor being
+/// forwarded to; authoritative servers queried iteratively get RD clear.
+pub fn lookup_once(
+    qname: &str,
+    qtype: QueryType,
+    server: (Ipv4Addr, u16),
+    recursion_desired: bool,
+    config: &ResolverConfig,
+) -> anyhow::Result<DnsPacket> {
+    // Let the OS pick the port, lookups running at the same time would
+    // collide on a fixed one.
+    let socket = UdpSocket::bind((config.source, 0))?;
+
+    let (id, qname) = if config.spoofing_protection {
+        (random_u64() as u16, randomize_case(qname))
+    } else {
+        (6666, qname.to_string())
+    };
+    let mut req_packet = DnsPacket::new()
+        .with_id(id)
+        .with_question(DnsQuestion::new(qname, qtype))
+        .with_recursion_desired(recursion_desired);
+    req_packet.resources.push(DnsRecord::Opt {
+        packet_len: config.edns_payload_size,
+        flags: 0,
+        options: vec![cookie_jar().option_for(server.0)],
+    });
+
+    let mut req_buffer = BytePacketBuffer::new();
+    req_packet.write(&mut req_buffer)?;
+
+    let size = (config.edns_payload_size as usize).max(MAX_BUFFER_SIZE);
+    let mut res_buffer = BytePacketBuffer::with_size(size);
+    let mut attempts = 0;
+    let len = loop {
+        send_all(&socket, &req_buffer.buffer[0..req_buffer.position], server)?;
+
+        match receive_response(&socket, &mut res_buffer, server, &req_packet, config) {
+            Ok(len) => break len,
+            Err(e)
+                if attempts < config.retries
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                attempts += 1;
+                log!("No response from {}:{}, retrying", server.0, server.1);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    DnsPacket::check_section_counts(&res_buffer.buffer[..len])?;
+
+    let mut res_buffer = BytePacketBuffer::from_bytes(&res_buffer.buffer[..len])?;
+    let res_packet = DnsPacket::from_buffer(&mut res_buffer)?;
+    cookie_jar().accept(server.0, &res_packet)?;
+
+    // A truncated response is missing records, so the whole answer is asked
+    // for again over TCP. The partial one is only used when that fails.
+    if res_packet.header.truncated_message {
+        log!(
+            "Truncated response from {}:{}, retrying over TCP",
+            server.0,
+            server.1
+        );
+        let request = req_buffer.get_range(0, req_buffer.position)?;
+        match lookup_tcp(request, id, server, config) {
+            Ok(packet) => return Ok(packet),
+            Err(e) => log!("TCP retry to {}:{} failed: {:#}", server.0, server.1, e),
+        }
+    }
+
+    Ok(res_packet)
+}
+
+fn write_tcp_message(stream: &mut TcpStream, data: &[u8]) -> anyhow::Result<()> {
+    let mut message = (data.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(data);
+    stream.write_all(&message)?;
+    Ok(())
+}
+
+fn read_tcp_message(stream: &mut TcpStream) -> anyhow::Result<BytePacketBuffer> {
+    let mut len_bytes = [0; 2];
+    stream.read_exact(&mut len_bytes)?;
+    let mut data = vec![0; u16::from_be_bytes(len_bytes) as usize];
+    stream.read_exact(&mut data)?;
+
+    BytePacketBuffer::from_bytes(&data)
+}
+
+fn lookup_tcp(
+    request: &[u8],
+    id: u16,
+    server: (Ipv4Addr, u16),
+    config: &ResolverConfig,
+) -> anyhow::Result<DnsPacket> {
+    let mut stream = TcpStream::connect_timeout(&SocketAddr::from(server), config.timeout)?;
+    stream.set_read_timeout(Some(config.timeout))?;
+    write_tcp_message(&mut stream, request)?;
+
+    let mut res_buffer = read_tcp_message(&mut stream)?;
+    let res_packet = DnsPacket::from_buffer(&mut res_buffer)?;
+    if res_packet.header.id != id {
+        return Err(TransportError::MismatchedResponse("ID").into());
+    }
+
+    Ok(res_packet)
+}
+
+/// Asks each of `forwarders` in turn with RD set, moving on to the next when
+/// one fails or answers SERVFAIL. The last forwarder's outcome is returned if
+/// none of them succeed.
+pub fn forward_lookup(
+    qname: &str,
+    qtype: QueryType,
+    forwarders: &[(Ipv4Addr, u16)],
+    config: &ResolverConfig,
+) -> anyhow::Result<DnsPacket> {
+    let mut result = Err(anyhow::anyhow!("No forwarders configured"));
+
+    for &forwarder in forwarders {
+        log!(
+            "Forwarding {:?} {} to {}:{}",
+            qtype,
+            qname,
+            forwarder.0,
+            forwarder.1
+        );
+
+        result = lookup_once(qname, qtype, forwarder, true, config);
+        match &result {
+            Ok(packet) if packet.header.rescode != ResultCode::ServFail => break,
+            Ok(_) => log!(
+                "Forwarder {}:{} answered SERVFAIL",
+                forwarder.0,
+                forwarder.1
+            ),
+            Err(e) => log!("Forwarder {}:{} failed: {}", forwarder.0, forwarder.1, e),
+        }
+    }
+
+    result
+}
+
+const DEFAULT_SYNTHESIZED_TTL: u32 = 300;
+
+pub type Hosts = HashMap<String, Vec<IpAddr>>;
+
+pub fn parse_hosts(contents: &str) -> Hosts {
+    let mut hosts = Hosts::new();
+
+    for line in contents.lines() {
+        let line = match line.find('#') {
+            Some(index) => &line[..index],
+            None => line,
+        };
+
+        let mut fields = line.split_whitespace();
+        let addr = match fields.next().and_then(|field| field.parse::<IpAddr>().ok()) {
+            Some(addr) => addr,
+            None => continue,
+        };
+
+        for name in fields.filter_map(|name| normalize_name(name).ok()) {
+            let addrs = hosts.entry(name).or_default();
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+
+    hosts
+}
+
+pub fn load_hosts<P: AsRef<Path>>(path: P) -> anyhow::Result<Hosts> {
+    Ok(parse_hosts(&fs::read_to_string(path)?))
+}
+
+#[derive(Debug, Error)]
+pub enum SubnetError {
+    #[error("Invalid subnet: {0}")]
+    InvalidSubnet(String),
+}
+
+/// An address prefix such as `192.0.2.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Subnet {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let full = prefix_len as usize / 8;
+    let rest = prefix_len % 8;
+
+    if net[..full] != addr[..full] {
+        return false;
+    }
+
+    rest == 0 || (net[full] ^ addr[full]) >> (8 - rest) == 0
+}
+
+impl FromStr for Subnet {
+    type Err = SubnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SubnetError::InvalidSubnet(s.to_string());
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix_len > max {
+            return Err(invalid());
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Static records served only to clients within `subnet`, for split-horizon
+/// setups.
+#[derive(Debug, Clone)]
+pub struct View {
+    pub subnet: Subnet,
+    pub hosts: Hosts,
+}
+
+fn address_record(domain: &str, addr: IpAddr, ttl: u32) -> DnsRecord {
+    match addr {
+        IpAddr::V4(addr) => DnsRecord::A {
+            domain: domain.to_string(),
+            addr,
+            ttl,
+        },
+        IpAddr::V6(addr) => DnsRecord::Aaaa {
+            domain: domain.to_string(),
+            addr,
+            ttl,
+        },
+    }
+}
+
+fn resolve_from_hosts(hosts: &Hosts, question: &DnsQuestion, ttl: u32) -> Option<DnsPacket> {
+    let addrs = hosts.get(&question.name.to_lowercase())?;
+
+    let records = addrs
+        .iter()
+        .filter(|addr| match question.qtype {
+            QueryType::A => addr.is_ipv4(),
+            QueryType::Aaaa => addr.is_ipv6(),
+            _ => false,
+        })
+        .map(|addr| address_record(&question.name, *addr, ttl))
+        .collect::<Vec<_>>();
+
+    if records.is_empty() {
+        None
+    } else {
+        let mut result = DnsPacket::new();
+        result.answers = records;
+        Some(result)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Blocklist {
+    names: HashSet<String>,
+    suffixes: HashSet<String>,
+}
+
+impl Blocklist {
+    pub fn parse(contents: &str) -> Self {
+        let mut blocklist = Self::default();
+
+        for line in contents.lines() {
+            let line = match line.find('#') {
+                Some(index) => &line[..index],
+                None => line,
+            };
+
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            match line.strip_prefix("*.") {
+                Some(suffix) => blocklist.suffixes.extend(normalize_name(suffix).ok()),
+                None => blocklist.names.extend(normalize_name(line).ok()),
+            }
+        }
+
+        blocklist
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn contains(&self, qname: &str) -> bool {
+        let qname = qname.to_lowercase();
+        if self.names.contains(&qname) {
+            return true;
+        }
+
+        let mut rest = qname.as_str();
+        while let Some((_, parent)) = rest.split_once('.') {
+            if self.suffixes.contains(parent) {
+                return true;
+            }
+            rest = parent;
+        }
+
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockAction {
+    #[default]
+    NxDomain,
+    Sinkhole,
+}
+
+fn resolve_blocked(
+    blocklist: &Blocklist,
+    action: BlockAction,
+    question: &DnsQuestion,
+    ttl: u32,
+) -> Option<DnsPacket> {
+    if !blocklist.contains(&question.name) {
+        return None;
+    }
+
+    log!("Blocked query: {:?}", question);
+    let mut result = DnsPacket::new();
+
+    match action {
+        BlockAction::NxDomain => result.header.rescode = ResultCode::NxDomain,
+        BlockAction::Sinkhole => {
+            let addr = match question.qtype {
+                QueryType::A => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                QueryType::Aaaa => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+                _ => None,
+            };
+            result
+                .answers
+                .extend(addr.map(|addr| address_record(&question.name, addr, ttl)));
+        }
+    }
+
+    Some(result)
+}
+
+fn resolve_special_name(question: &DnsQuestion, answer_local: bool, ttl: u32) -> Option<DnsPacket> {
+    let name = question.name.to_ascii_lowercase();
+    let name = name.trim_end_matches('.');
+    let mut result = DnsPacket::new();
+
+    if name == "localhost" || name.ends_with(".localhost") {
+        let addr = match question.qtype {
+            QueryType::A => Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            QueryType::Aaaa => Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            _ => None,
+        };
+        result
+            .answers
+            .extend(addr.map(|addr| address_record(&question.name, addr, ttl)));
+        return Some(result);
+    }
+
+    if answer_local && (name == "local" || name.ends_with(".local")) {
+        result.header.rescode = ResultCode::NxDomain;
+        return Some(result);
+    }
+
+    None
+}
+
+/// Answers the `version.bind`-style TXT probes sent in the CHAOS class with
+/// the configured strings, refusing them when nothing is configured. Other
+/// CHAOS queries are refused too. The answer is written in class CH since
+/// the question is.
+fn resolve_chaos(context: &ServerContext, question: &DnsQuestion) -> DnsPacket {
+    let name = question.name.to_ascii_lowercase();
+    let text = match (name.trim_end_matches('.'), question.qtype) {
+        ("version.bind" | "version.server", QueryType::Txt) => context.chaos_version.as_ref(),
+        ("hostname.bind" | "id.server", QueryType::Txt) => context.chaos_hostname.as_ref(),
+        _ => None,
+    };
+
+    let mut result = DnsPacket::new();
+    match text {
+        Some(text) => result.answers.push(DnsRecord::Txt {
+            domain: question.name.clone(),
+            data: vec![text.clone()],
+            ttl: 0,
+        }),
+        None => result.header.rescode = ResultCode::Refused,
+    }
+
+    result
+}
+
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub origin: String,
+    pub soa: DnsRecord,
+    pub nameservers: Vec<String>,
+    pub records: Vec<DnsRecord>,
+}
+
+impl Zone {
+    pub fn new(origin: &str, soa: DnsRecord) -> Self {
+        Self {
+            origin: origin.to_lowercase(),
+            soa,
+            nameservers: Vec::new(),
+            records: Vec::new(),
+        }
+    }
+
+    fn apex_records(&self) -> Vec<DnsRecord> {
+        let mut records = vec![self.soa.clone()];
+
+        for host in &self.nameservers {
+            records.push(DnsRecord::Ns {
+                domain: self.origin.clone(),
+                host: host.clone(),
+                ttl: self.soa.ttl(),
+            });
+        }
+
+        records
+    }
+
+    /// Fetches the zone from `primary` with an AXFR over TCP. The transfer
+    /// is the stream of records between the leading and trailing SOA, and
+    /// the configured timeout bounds connecting as well as each read.
+    pub fn transfer(
+        origin: &str,
+        primary: SocketAddr,
+        config: &ResolverConfig,
+    ) -> anyhow::Result<Zone> {
+        let mut stream = TcpStream::connect_timeout(&primary, config.timeout)?;
+        stream.set_read_timeout(Some(config.timeout))?;
+
+        let mut req_packet = DnsPacket::new()
+            .with_id(6666)
+            .with_question(DnsQuestion::new(origin.to_string(), QueryType::Axfr));
+        let mut req_buffer = BytePacketBuffer::new();
+        req_packet.write(&mut req_buffer)?;
+
+        let data = req_buffer.get_range(0, req_buffer.position)?;
+        write_tcp_message(&mut stream, data)?;
+
+        let mut soa = None;
+        let mut records = Vec::new();
+
+        loop {
+            let mut res_buffer = read_tcp_message(&mut stream)?;
+            let res_packet = DnsPacket::from_buffer(&mut res_buffer)?;
+            if res_packet.header.rescode != ResultCode::NoError {
+                anyhow::bail!(
+                    "Transfer of {} failed: {}",
+                    origin,
+                    res_packet.header.rescode
+                );
+            }
+
+            for record in res_packet.answers {
+                match (&soa, record) {
+                    (None, record @ DnsRecord::Soa { .. }) => soa = Some(record),
+                    (None, _) => anyhow::bail!("Transfer of {} didn't start with an SOA", origin),
+                    (Some(soa), DnsRecord::Soa { .. }) => {
+                        let mut zone = Zone::new(origin, soa.clone());
+                        for record in records {
+                            match record {
+                                DnsRecord::Ns { domain, host, .. }
+                                    if domain.eq_ignore_ascii_case(&zone.origin) =>
+                                {
+                                    zone.nameservers.push(host)
+                                }
+                                record => zone.records.push(record),
+                            }
+                        }
+                        return Ok(zone);
+                    }
+                    (Some(_), record) => records.push(record),
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, qname: &str) -> bool {
+        is_in_bailiwick(&qname.to_lowercase(), &self.origin)
+    }
+
+    fn resolve(&self, question: &DnsQuestion) -> DnsPacket {
+        let answers = self
+            .apex_records()
+            .iter()
+            .chain(self.records.iter())
+            .filter(|record| question.matches(record))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let name_exists = question.name.eq_ignore_ascii_case(&self.origin)
+            || self
+                .records
+                .iter()
+                .any(|record| record.domain().eq_ignore_ascii_case(&question.name));
+
+        let mut result = if name_exists {
+            let mut result = DnsPacket::new();
+            if answers.is_empty() {
+                result.authorities.push(self.soa.clone());
+            }
+            result.answers = answers;
+            result
+        } else {
+            DnsPacket::nxdomain_for(question.clone(), self.soa.clone())
+        };
+
+        result.header.authoritative_answer = true;
+        result
+    }
+}
+
+fn resolve_from_zones(zones: &[Zone], question: &DnsQuestion) -> Option<DnsPacket> {
+    zones
+        .iter()
+        .filter(|zone| zone.contains(&question.name))
+        .max_by_key(|zone| label_count(&zone.origin))
+        .map(|zone| zone.resolve(question))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateOperation {
+    Add(DnsRecord),
+    DeleteRecord(DnsRecord),
+    DeleteRrset(String, QueryType),
+    DeleteName(String),
+}
+
+impl UpdateOperation {
+    fn name(&self) -> &str {
+        match self {
+            UpdateOperation::Add(record) | UpdateOperation::DeleteRecord(record) => record.domain(),
+            UpdateOperation::DeleteRrset(name, _) | UpdateOperation::DeleteName(name) => name,
+        }
+    }
+}
+
+/// A dynamic update (RFC 2136). It shares the layout of a query, but the
+/// sections hold the zone, the prerequisites, the updates and additional
+/// records, and the class of each update record selects the operation.
+#[derive(Debug, Clone)]
+pub struct UpdateMessage {
+    pub header: DnsHeader,
+    pub zone: DnsQuestion,
+    pub operations: Vec<UpdateOperation>,
+}
+
+impl UpdateMessage {
+    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> anyhow::Result<UpdateMessage> {
+        let mut header = DnsHeader::new();
+        header.read(buffer).context("failed parsing header")?;
+
+        if header.questions != 1 {
+            anyhow::bail!("Update has {} zones instead of one", header.questions);
+        }
+        if header.answers > 0 {
+            anyhow::bail!("Update prerequisites aren't supported");
+        }
+
+        let mut zone = DnsQuestion::new(String::new(), QueryType::Unknown(0));
+        zone.read(buffer).context("failed parsing zone")?;
+
+        let mut operations = Vec::new();
+        for i in 0..header.authoritative_entries {
+            let operation = Self::read_operation(buffer)
+                .with_context(|| format!("failed parsing update {}", i + 1))?;
+            operations.push(operation);
+        }
+
+        Ok(UpdateMessage {
+            header,
+            zone,
+            operations,
+        })
+    }
+
+    fn read_operation(buffer: &mut BytePacketBuffer) -> anyhow::Result<UpdateOperation> {
+        let start = buffer.position();
+
+        let mut domain = String::new();
+        buffer.read_qname(&mut domain)?;
+        let qtype = QueryType::from(buffer.read_u16()?);
+        let class = QueryClass::from(buffer.read_u16()?);
+        let _ = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+
+        let operation = match class {
+            QueryClass::Any if qtype == QueryType::Any => UpdateOperation::DeleteName(domain),
+            QueryClass::Any => UpdateOperation::DeleteRrset(domain, qtype),
+            QueryClass::None => {
+                buffer.seek(start)?;
+                return Ok(UpdateOperation::DeleteRecord(DnsRecord::read(buffer)?));
+            }
+            _ => {
+                buffer.seek(start)?;
+                return Ok(UpdateOperation::Add(DnsRecord::read(buffer)?));
+            }
+        };
+        buffer.step(data_len as usize)?;
+
+        Ok(operation)
+    }
+}
+
+fn apply_update(context: &ServerContext, update: &UpdateMessage) -> ResultCode {
+    let mut zones = context.zones.write().unwrap();
+    let zone = match zones
+        .iter_mut()
+        .find(|zone| zone.origin.eq_ignore_ascii_case(&update.zone.name))
+    {
+        Some(zone) => zone,
+        None => return ResultCode::NotAuth,
+    };
+
+    if update
+        .operations
+        .iter()
+        .any(|operation| !zone.contains(operation.name()))
+    {
+        return ResultCode::NotZone;
+    }
+
+    let before = zone.records.clone();
+    for operation in &update.operations {
+        log!("Applying update: {:?}", operation);
+
+        match operation {
+            UpdateOperation::Add(record) => {
+                if !zone.records.iter().any(|other| other.same_rr(record)) {
+                    zone.records.push(record.clone());
+                }
+            }
+            UpdateOperation::DeleteRecord(record) => {
+                zone.records.retain(|other| !other.same_rr(record));
+            }
+            UpdateOperation::DeleteRrset(name, qtype) => zone.records.retain(|other| {
+                !(other.domain().eq_ignore_ascii_case(name) && other.query_type() == *qtype)
+            }),
+            UpdateOperation::DeleteName(name) => zone
+                .records
+                .retain(|other| !other.domain().eq_ignore_ascii_case(name)),
+        }
+    }
+
+    // Secondaries only transfer the zone again once they see the serial move.
+    if zone.records != before {
+        if let DnsRecord::Soa { serial, .. } = &mut zone.soa {
+            *serial = serial.wrapping_add(1);
+        }
+    }
+
+    ResultCode::NoError
+}
+
+fn resolve_locally(
+    context: &ServerContext,
+    question: &DnsQuestion,
+    client: IpAddr,
+) -> Option<DnsPacket> {
+    let ttl = context.synthesized_ttl.unwrap_or(DEFAULT_SYNTHESIZED_TTL);
+    let view = context
+        .views
+        .iter()
+        .find(|view| view.subnet.contains(client));
+
+    resolve_special_name(question, context.answer_local, ttl)
+        .or_else(|| resolve_blocked(&context.blocklist, context.block_action, question, ttl))
+        .or_else(|| view.and_then(|view| resolve_from_hosts(&view.hosts, question, ttl)))
+        .or_else(|| resolve_from_zones(&context.zones.read().unwrap(), question))
+        .or_else(|| resolve_from_hosts(&context.hosts, question, ttl))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDirection {
+    Query,
+    Response,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureFrame {
+    pub direction: CaptureDirection,
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+// Each frame is laid out as: direction (u8), seconds (u64), nanoseconds (u32),
+// length (u16) and the raw packet bytes, all integers in network byte order.
+fn write_capture_frame(
+    mut out: impl Write,
+    direction: CaptureDirection,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+    let mut frame = Vec::with_capacity(15 + data.len());
+    frame.push(direction as u8);
+    frame.extend_from_slice(&timestamp.as_secs().to_be_bytes());
+    frame.extend_from_slice(&timestamp.subsec_nanos().to_be_bytes());
+    frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    frame.extend_from_slice(data);
+
+    out.write_all(&frame)?;
+    Ok(())
+}
+
+pub fn read_capture<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<CaptureFrame>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let mut frames = Vec::new();
+    let mut rest = bytes.as_slice();
+
+    while !rest.is_empty() {
+        if rest.len() < 15 {
+            return Err(BytePacketBufferError::EndOfBuffer.into());
+        }
+
+        let direction = match rest[0] {
+            0 => CaptureDirection::Query,
+            _ => CaptureDirection::Response,
+        };
+        let secs = u64::from_be_bytes(rest[1..9].try_into()?);
+        let nanos = u32::from_be_bytes(rest[9..13].try_into()?);
+        let len = u16::from_be_bytes(rest[13..15].try_into()?) as usize;
+
+        if rest.len() < 15 + len {
+            return Err(BytePacketBufferError::EndOfBuffer.into());
+        }
+
+        frames.push(CaptureFrame {
+            direction,
+            timestamp: Duration::new(secs, nanos),
+            data: rest[15..15 + len].to_vec(),
+        });
+        rest = &rest[15 + len..];
+    }
+
+    Ok(frames)
+}
+
+const STALE_TTL: u32 = 30;
+const DEFAULT_CACHE_SIZE: usize = 10_000;
+/// How long past its TTL an entry is kept around for serving stale.
+const STALE_RETENTION: u32 = 86_400;
+
+pub trait CacheStore: Send + Sync {
+    fn get(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket>;
+    fn put(&self, qname: &str, qtype: QueryType, packet: DnsPacket, ttl: u32);
+    fn remove(&self, qname: &str, qtype: QueryType);
+
+    fn get_stale(&self, _qname: &str, _qtype: QueryType, _max_stale: u32) -> Option<DnsPacket> {
+        None
+    }
+
+    fn cached_types(&self, _qname: &str) -> Vec<QueryType> {
+        Vec::new()
+    }
+
+    /// The NS records and glue a referral gave for `zone`. These are kept
+    /// apart from answers, so they are never served to clients.
+    fn get_delegation(&self, _zone: &str) -> Option<DnsPacket> {
+        None
+    }
+
+    fn put_delegation(&self, _zone: &str, _packet: DnsPacket, _ttl: u32) {}
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    packet: DnsPacket,
+    stored_at: Instant,
+    last_used: Instant,
+    ttl: u32,
+}
+
+impl CacheEntry {
+    fn is_discardable(&self) -> bool {
+        self.stored_at.elapsed().as_secs() > self.ttl.saturating_add(STALE_RETENTION) as u64
+    }
+}
+
+/// An in-memory cache holding up to `capacity` entries. Expired entries are
+/// kept for serving stale until `STALE_RETENTION` has passed, and once the
+/// cache is full they go first, then the least recently used ones.
+#[derive(Debug)]
+pub struct Cache {
+    entries: Mutex<HashMap<(String, QueryType), CacheEntry>>,
+    delegations: Mutex<HashMap<String, CacheEntry>>,
+    capacity: usize,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            delegations: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    fn make_room<K: Clone + Eq + Hash>(&self, entries: &mut HashMap<K, CacheEntry>) {
+        entries.retain(|_, entry| !entry.is_discardable());
+
+        while entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    /// The cached names and types with the seconds each has left. Expired
+    /// entries still kept for serving stale are listed with 0.
+    pub fn entries(&self) -> Vec<(String, QueryType, u32)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((qname, qtype), entry)| {
+                let elapsed = entry.stored_at.elapsed().as_secs() as u32;
+                (qname.clone(), *qtype, entry.ttl.saturating_sub(elapsed))
+            })
+            .collect()
+    }
+
+    pub fn preload(&self, records: Vec<DnsRecord>) {
+        let mut groups: HashMap<(String, QueryType), Vec<DnsRecord>> = HashMap::new();
+        for record in records {
+            let key = (record.domain().to_lowercase(), record.query_type());
+            groups.entry(key).or_default().push(record);
+        }
+
+        for ((qname, qtype), records) in groups {
+            let ttl = records.iter().map(DnsRecord::ttl).min().unwrap_or(0);
+            let mut packet = DnsPacket::new();
+            packet.answers = records;
+            self.put(&qname, qtype, packet, ttl);
+        }
+    }
+}
+
+impl CacheStore for Cache {
+    fn get(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let key = (qname.to_lowercase(), qtype);
+        let mut entries = self.entries.lock().unwrap();
+
+        let entry = entries.get_mut(&key)?;
+        let elapsed = entry.stored_at.elapsed().as_secs() as u32;
+        if entry.is_discardable() {
+            entries.remove(&key);
+            return None;
+        }
+        if elapsed >= entry.ttl {
+            return None;
+        }
+        entry.last_used = Instant::now();
+
+        let mut packet = entry.packet.clone();
+        for record in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            record.set_ttl(record.ttl().saturating_sub(elapsed));
+        }
+
+        Some(packet)
+    }
+
+    fn put(&self, qname: &str, qtype: QueryType, packet: DnsPacket, ttl: u32) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let entry = CacheEntry {
+            packet,
+            stored_at: now,
+            last_used: now,
+            ttl,
+        };
+
+        let key = (qname.to_lowercase(), qtype);
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) && entries.len() >= self.capacity {
+            self.make_room(&mut entries);
+        }
+        entries.insert(key, entry);
+    }
+
+    fn remove(&self, qname: &str, qtype: QueryType) {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&(qname.to_lowercase(), qtype));
+    }
+
+    fn cached_types(&self, qname: &str) -> Vec<QueryType> {
+        let qname = qname.to_lowercase();
+        self.entries
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(name, _)| *name == qname)
+            .map(|(_, qtype)| *qtype)
+            .collect()
+    }
+
+    fn get_stale(&self, qname: &str, qtype: QueryType, max_stale: u32) -> Option<DnsPacket> {
+        let entries = self.entries.lock().unwrap();
+
+        let entry = entries.get(&(qname.to_lowercase(), qtype))?;
+        let elapsed = entry.stored_at.elapsed().as_secs() as u32;
+        if elapsed > entry.ttl.saturating_add(max_stale) {
+            return None;
+        }
+
+        let mut packet = entry.packet.clone();
+        for record in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            record.set_ttl(STALE_TTL);
+        }
+
+        Some(packet)
+    }
+
+    fn get_delegation(&self, zone: &str) -> Option<DnsPacket> {
+        let mut delegations = self.delegations.lock().unwrap();
+
+        let entry = delegations.get_mut(&zone.to_lowercase())?;
+        if entry.stored_at.elapsed().as_secs() >= entry.ttl as u64 {
+            return None;
+        }
+        entry.last_used = Instant::now();
+
+        Some(entry.packet.clone())
+    }
+
+    fn put_delegation(&self, zone: &str, packet: DnsPacket, ttl: u32) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let entry = CacheEntry {
+            packet,
+            stored_at: now,
+            last_used: now,
+            ttl,
+        };
+
+        let zone = zone.to_lowercase();
+        let mut delegations = self.delegations.lock().unwrap();
+        if !delegations.contains_key(&zone) && delegations.len() >= self.capacity {
+            self.make_room(&mut delegations);
+        }
+        delegations.insert(zone, entry);
+    }
+}
+
+fn cache_ttl(packet: &DnsPacket) -> Option<u32> {
+    // Records may be missing from a truncated response.
+    if packet.header.truncated_message {
+        return None;
+    }
+
+    match packet.header.rescode {
+        ResultCode::NoError if !packet.answers.is_empty() => {
+            packet.answers.iter().map(DnsRecord::ttl).min()
+        }
+        ResultCode::NoError | ResultCode::NxDomain => packet.negative_ttl(),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    released: Condvar,
+}
+
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+}
+
+impl InFlight {
+    fn acquire(&self, limit: usize, wait: bool) -> Option<InFlightGuard<'_>> {
+        let mut count = self.count.lock().unwrap();
+
+        while *count >= limit {
+            if !wait {
+                return None;
+            }
+            count = self.released.wait(count).unwrap();
+        }
+
+        *count += 1;
+        Some(InFlightGuard { in_flight: self })
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        *self.in_flight.count.lock().unwrap() -= 1;
+        self.in_flight.released.notify_one();
+    }
+}
+
+/// Answers an ANY query from whatever is cached for the name, or with the
+/// RFC 8482 HINFO placeholder when `minimal_any` is set. Returns `None` when
+/// nothing is cached, so the query gets forwarded as usual.
+fn resolve_any(
+    context: &ServerContext,
+    cache: &dyn CacheStore,
+    question: &DnsQuestion,
+) -> Option<DnsPacket> {
+    let mut result = DnsPacket::new();
+
+    if context.minimal_any {
+        result.answers.push(DnsRecord::Hinfo {
+            domain: question.name.clone(),
+            cpu: "RFC8482".to_string(),
+            os: String::new(),
+            ttl: context.synthesized_ttl.unwrap_or(DEFAULT_SYNTHESIZED_TTL),
+        });
+        return Some(result);
+    }
+
+    for qtype in cache.cached_types(&question.name) {
+        if let Some(packet) = cache.get(&question.name, qtype) {
+            result.answers.extend(
+                packet
+                    .answers
+                    .into_iter()
+                    .filter(|record| record.domain().eq_ignore_ascii_case(&question.name)),
+            );
+        }
+    }
+
+    if result.answers.is_empty() {
+        return None;
+    }
+
+    log!("Answering ANY from cache: {:?}", question);
+    Some(result)
+}
+
+#[derive(Debug, Default)]
+struct Call {
+    result: Mutex<Option<Result<DnsPacket, String>>>,
+    done: Condvar,
+}
+
+/// Lets concurrent lookups of the same name and type share one resolution.
+#[derive(Debug, Default)]
+struct SingleFlight {
+    calls: Mutex<HashMap<(String, QueryType), Arc<Call>>>,
+}
+
+impl SingleFlight {
+    fn run<F>(&self, key: (String, QueryType), lookup: F) -> anyhow::Result<DnsPacket>
+    where
+        F: FnOnce() -> anyhow::Result<DnsPacket>,
+    {
+        let (call, leader) = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&key) {
+                Some(call) => (call.clone(), false),
+                None => {
+                    let call = Arc::new(Call::default());
+                    calls.insert(key.clone(), call.clone());
+                    (call, true)
+                }
+            }
+        };
+
+        if !leader {
+            log!("Waiting on lookup in flight: {:?}", key);
+            let mut result = call.result.lock().unwrap();
+            while result.is_none() {
+                result = call.done.wait(result).unwrap();
+            }
+            // Errors don't clone, so waiters get the leader's as a message.
+            return result.clone().unwrap().map_err(anyhow::Error::msg);
+        }
+
+        let leader = Leader {
+            flight: self,
+            key: &key,
+            call: &call,
+        };
+        let result = lookup();
+        *call.result.lock().unwrap() = Some(match &result {
+            Ok(packet) => Ok(packet.clone()),
+            Err(e) => Err(format!("{:#}", e)),
+        });
+        drop(leader);
+
+        result
+    }
+}
+
+/// Hands the call over to its waiters when the leader is done with it. This
+/// also runs when the lookup panics, in which case they get an error.
+struct Leader<'a> {
+    flight: &'a SingleFlight,
+    key: &'a (String, QueryType),
+    call: &'a Call,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        let mut result = self.call.result.lock().unwrap();
+        if result.is_none() {
+            *result = Some(Err("Lookup in flight panicked".to_string()));
+        }
+        drop(result);
+
+        self.flight.calls.lock().unwrap().remove(self.key);
+        self.call.done.notify_all();
+    }
+}
+
+fn resolve_with_cache(
+    context: &ServerContext,
+    cache: &dyn CacheStore,
+    question: &DnsQuestion,
+    query: &QueryContext,
+) -> anyhow::Result<DnsPacket> {
+    if question.qtype == QueryType::Any {
+        if let Some(packet) = resolve_any(context, cache, question) {
+            return Ok(packet);
+        }
+    }
+
+    if let Some(packet) = cache.get(&question.name, question.qtype) {
+        log!("Cache hit: {:?}", question);
+        return Ok(packet);
+    }
+
+    let key = (question.name.to_lowercase(), question.qtype);
+    let result = context.single_flight.run(key, || {
+        let _guard = match context.max_in_flight {
+            Some(limit) => match context.in_flight.acquire(limit, context.queue_when_busy) {
+                Some(guard) => Some(guard),
+                None => {
+                    log!("Refusing query, too many lookups in flight: {:?}", question);
+                    let mut packet = DnsPacket::new();
+                    packet.header.rescode = ResultCode::Refused;
+                    return Ok(packet);
+                }
+            },
+            None => None,
+        };
+
+        if !context.forwarders.is_empty() {
+            return forward_lookup(
+                &question.name,
+                question.qtype,
+                &context.forwarders,
+                &context.resolver,
+            );
+        }
+
+        recursive_lookup(
+            &question.name,
+            question.qtype,
+            &context.resolver,
+            &context.rtts,
+            cache,
+            query,
+        )
+    });
+
+    let mut packet = match result {
+        Ok(packet) => packet,
+        Err(e) => {
+            let stale = context
+                .serve_stale
+                .and_then(|max_stale| cache.get_stale(&question.name, question.qtype, max_stale));
+
+            return match stale {
+                Some(packet) => {
+                    log!("Serving stale answer after error ({}): {:?}", e, question);
+                    Ok(packet)
+                }
+                None => Err(e),
+            };
+        }
+    };
+    packet.header.authoritative_answer = false;
+    packet.synthesize_dname_cname(&question.name);
+    packet.clamp_ttls(context.min_ttl, context.max_ttl);
+    if let Some(ttl) = cache_ttl(&packet) {
+        cache.put(&question.name, question.qtype, packet.clone(), ttl);
+    }
+
+    Ok(packet)
+}
+
+pub fn bind_sockets(port: u16) -> anyhow::Result<Vec<UdpSocket>> {
+    bind_dual_stack(port, UdpSocket::bind)
+}
+
+pub fn bind_listeners(port: u16) -> anyhow::Result<Vec<TcpListener>> {
+    bind_dual_stack(port, TcpListener::bind)
+}
+
+fn bind_dual_stack<T>(
+    port: u16,
+    bind: impl Fn(SocketAddr) -> io::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    let mut sockets = Vec::new();
+
+    match bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))) {
+        Ok(socket) => sockets.push(socket),
+        Err(e) => log!("Couldn't bind to [::]:{}: {}", port, e),
+    }
+
+    // On dual-stack hosts the IPv6 socket already accepts IPv4 traffic, so
+    // failing to bind the IPv4 wildcard is only fatal when it is the sole socket.
+    match bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))) {
+        Ok(socket) => sockets.push(socket),
+        Err(e) if sockets.is_empty() => return Err(e.into()),
+        Err(_) => {}
+    }
+
+    Ok(sockets)
+}
+
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_TCP_CONNECTIONS: usize = 128;
+
+pub const OPCODE_QUERY: u8 = 0;
+pub const OPCODE_NOTIFY: u8 = 4;
+pub const OPCODE_UPDATE: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+#[derive(Debug, Default)]
+pub struct ServerContext {
+    pub hosts: Hosts,
+    /// Checked before everything but special names and the blocklist. The
+    /// first view whose subnet holds the client answers.
+    pub views: Vec<View>,
+    /// Forwarders whose EDNS Client Subnet options are believed. Anyone else
+    /// gets the view for their own address.
+    pub trusted_ecs_sources: Vec<Subnet>,
+    pub zones: RwLock<Vec<Zone>>,
+    pub blocklist: Blocklist,
+    pub block_action: BlockAction,
+    pub capture: Option<File>,
+    pub preserve_name_case: bool,
+    pub chaos_version: Option<String>,
+    pub chaos_hostname: Option<String>,
+    pub strict_question_names: bool,
+    pub answer_local: bool,
+    pub authoritative_only: bool,
+    /// Opcodes that get served, anything else is answered with NOTIMP. Only
+    /// standard queries when unset.
+    pub allowed_opcodes: Option<Vec<u8>>,
+    /// Where dynamic updates are accepted from. Updates from anywhere else
+    /// are refused.
+    pub update_sources: Vec<Subnet>,
+    pub minimal_any: bool,
+    /// Caps the answer section, setting TC when records had to be dropped.
+    pub max_answers: Option<usize>,
+    pub preferred_family: Option<AddressFamily>,
+    /// Recursors to forward cache misses to, tried in order, instead of
+    /// resolving from the roots.
+    pub forwarders: Vec<(Ipv4Addr, u16)>,
+    pub resolver: ResolverConfig,
+    pub rtts: RttTracker,
+    pub max_in_flight: Option<usize>,
+    pub queue_when_busy: bool,
+    /// How many TCP connections are served at once, 128 when unset. Any
+    /// more are closed as soon as they are accepted.
+    pub max_tcp_connections: Option<usize>,
+    pub serve_stale: Option<u32>,
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+    pub synthesized_ttl: Option<u32>,
+    /// Resolutions taking longer than this get logged.
+    pub slow_resolution: Option<Duration>,
+    /// Block size to pad responses to for clients that send the EDNS padding
+    /// option.
+    pub padding_block: Option<usize>,
+    pub on_response: Option<ResponseHook>,
+    /// Holds every response back for this long, for exercising clients'
+    /// timeout handling. Only available in debug builds. UDP queries are
+    /// answered one at a time, so the delay also holds back every query
+    /// queued behind the delayed one.
+    #[cfg(debug_assertions)]
+    pub response_delay: Option<Duration>,
+    in_flight: InFlight,
+    tcp_connections: InFlight,
+    single_flight: SingleFlight,
+}
+
+/// Called with every response just before it is serialized, so embedders can
+/// rewrite it.
+pub struct ResponseHook(Box<dyn Fn(&mut DnsPacket) + Send + Sync>);
+
+impl ResponseHook {
+    pub fn new<F: Fn(&mut DnsPacket) + Send + Sync + 'static>(hook: F) -> Self {
+        Self(Box::new(hook))
+    }
+}
+
+impl fmt::Debug for ResponseHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseHook")
+    }
+}
+
+fn resolve_question(
+    context: &ServerContext,
+    cache: &dyn CacheStore,
+    query: &QueryContext,
+    client: IpAddr,
+) -> anyhow::Result<DnsPacket> {
+    let question = &query.question;
+    let question = match normalize_name(&question.name) {
+        Ok(name) => DnsQuestion {
+            name,
+            qtype: question.qtype,
+            qclass: question.qclass,
+        },
+        Err(e) => {
+            log!("Rejecting query: {}", e);
+            let mut result = DnsPacket::new();
+            result.header.rescode = ResultCode::FormErr;
+            return Ok(result);
+        }
+    };
+
+    match question.qclass {
+        QueryClass::Ch => return Ok(resolve_chaos(context, &question)),
+        // Only Internet data is served, so ANY gets the same answers as IN.
+        QueryClass::In | QueryClass::Any => {}
+        qclass => {
+            log!("Refusing query for class {}: {:?}", qclass, question);
+            let mut result = DnsPacket::new();
+            result.header.rescode = ResultCode::Refused;
+            return Ok(result);
+        }
+    }
+
+    match resolve_locally(context, &question, client) {
+        Some(result) => Ok(result),
+        None if context.authoritative_only => {
+            log!("Refusing query outside configured zones: {:?}", question);
+            let mut result = DnsPacket::new();
+            result.header.rescode = ResultCode::Refused;
+            Ok(result)
+        }
+        None => resolve_with_cache(context, cache, &question, query),
+    }
+}
+
+/// Whether clients can count on this server to recurse for them, which is
+/// what RA advertises.
+fn offers_recursion(context: &ServerContext) -> bool {
+    !context.authoritative_only
+}
+
+fn opcode_allowed(context: &ServerContext, opcode: u8) -> bool {
+    context
+        .allowed_opcodes
+        .as_deref()
+        .unwrap_or(&[OPCODE_QUERY])
+        .contains(&opcode)
+}
+
+/// An empty response echoing what `req_header` asks to have echoed.
+fn reply_to(context: &ServerContext, req_header: &DnsHeader) -> DnsPacket {
+    let mut res_packet = DnsPacket::new();
+    res_packet.header.id = req_header.id;
+    res_packet.header.opcode = req_header.opcode;
+    res_packet.header.recursion_desired = req_header.recursion_desired;
+    res_packet.header.recursion_available = offers_recursion(context);
+    res_packet.header.response = true;
+    res_packet
+}
+
+fn build_update_response(
+    context: &ServerContext,
+    req_buffer: &mut BytePacketBuffer,
+    req_header: &DnsHeader,
+    src: SocketAddr,
+) -> DnsPacket {
+    let mut res_packet = reply_to(context, req_header);
+
+    let permitted = context
+        .update_sources
+        .iter()
+        .any(|subnet| subnet.contains(src.ip()));
+    if !permitted {
+        log!("Refusing update from {}", src);
+        res_packet.header.rescode = ResultCode::Refused;
+        return res_packet;
+    }
+
+    let update = req_buffer
+        .seek(0)
+        .and_then(|_| UpdateMessage::from_buffer(req_buffer));
+    res_packet.header.rescode = match update {
+        Ok(update) => {
+            res_packet.questions.push(update.zone.clone());
+            apply_update(context, &update)
+        }
+        Err(e) => {
+            log!("Rejecting update: {:#}", e);
+            ResultCode::FormErr
+        }
+    };
+
+    res_packet
+}
+
+fn build_response(
+    context: &ServerContext,
+    cache: &dyn CacheStore,
+    req_packet: &DnsPacket,
+    src: SocketAddr,
+) -> DnsPacket {
+    let mut res_packet = req_packet.into_response();
+    res_packet.header.recursion_available = offers_recursion(context);
+    let mut client_subnet = None;
+
+    if !opcode_allowed(context, req_packet.header.opcode) {
+        log!("Rejecting opcode {}", req_packet.header.opcode);
+        res_packet.header.rescode = ResultCode::NotImp;
+    } else if let Some(question) = req_packet.question() {
+        log!("Received query: {:?}", question);
+
+        // A client subnet option speaks for the client behind a forwarder,
+        // but only trusted forwarders get to pick the view that answers.
+        let trusted = context
+            .trusted_ecs_sources
+            .iter()
+            .any(|subnet| subnet.contains(src.ip()));
+        if trusted {
+            client_subnet = req_packet.client_subnet().map(|subnet| {
+                // The answer only depends on the subnet when a view has the
+                // name.
+                let in_view = normalize_name(&question.name).is_ok_and(|name| {
+                    context
+                        .views
+                        .iter()
+                        .any(|view| view.hosts.contains_key(&name))
+                });
+                (subnet, if in_view { subnet.prefix_len } else { 0 })
+            });
+        }
+        let client = client_subnet.map_or(src.ip(), |(subnet, _)| subnet.addr);
+
+        let started = Instant::now();
+        let query = QueryContext::new(req_packet.header.id, question.clone())
+            .with_deadline(started + context.resolver.budget);
+        let result = resolve_question(context, cache, &query, client);
+        if let Some(warning) =
+            slow_resolution_warning(context.slow_resolution, question, started.elapsed())
+        {
+            log!("{}", warning);
+        }
+        let servers = query.servers();
+        if !servers.is_empty() {
+            log!(
+                "Asked {} servers, chased {} delegations",
+                servers.len(),
+                query.delegations().len()
+            );
+        }
+
+        if let Ok(result) = result {
+            res_packet.header.rescode = result.header.rescode;
+            res_packet.header.authoritative_answer = result.header.authoritative_answer;
+
+            for record in result.answers {
+                log!("Answer: {:?}", record);
+                res_packet.answers.push(record);
+            }
+
+            for record in result.authorities {
+                log!("Authority: {:?}", record);
+                res_packet.authorities.push(record);
+            }
+
+            for record in result.resources {
+                // OPT and TSIG only apply to the hop they arrived on.
+                if let DnsRecord::Opt { .. } | DnsRecord::Tsig { .. } = record {
+                    continue;
+                }
+
+                log!("Resource: {:?}", record);
+                res_packet.resources.push(record);
+            }
+        } else {
+            res_packet.header.rescode = ResultCode::ServFail;
+        }
+    } else {
+        res_packet.header.rescode = ResultCode::FormErr;
+    }
+
+    if let Some(family) = context.preferred_family {
+        res_packet.prefer_family(family == AddressFamily::V6);
+    }
+
+    if let Some(max) = context.max_answers {
+        if res_packet.answers.len() > max {
+            log!("Truncating {} answers to {}", res_packet.answers.len(), max);
+            res_packet.answers.truncate(max);
+            res_packet.header.truncated_message = true;
+        }
+    }
+
+    res_packet.add_opt_for(req_packet);
+    if let Some((subnet, scope_prefix_len)) = client_subnet {
+        res_packet.add_edns_option(EdnsOption::client_subnet(subnet, scope_prefix_len));
+    }
+    // Responses are only padded for clients that pad their queries.
+    if context.padding_block.is_some() && req_packet.has_edns_option(EDNS_OPTION_PADDING) {
+        res_packet.add_edns_option(EdnsOption {
+            code: EDNS_OPTION_PADDING,
+            data: Vec::new(),
+        });
+    }
+
+    res_packet
+}
+
+/// The warning to log for a resolution of `question` that took `elapsed`,
+/// if that's over the `threshold`.
+fn slow_resolution_warning(
+    threshold: Option<Duration>,
+    question: &DnsQuestion,
+    elapsed: Duration,
+) -> Option<String> {
+    threshold.filter(|&threshold| elapsed > threshold)?;
+    Some(format!(
+        "Slow resolution of {:?} {} took {:?}",
+        question.qtype, question.name, elapsed
+    ))
+}
+
+/// Parses the query in the first `len` bytes of `req_buffer` and assembles
+/// the response to it, without serializing or sending it.
+pub fn resolve_request(
+    context: &ServerContext,
+    cache: &dyn CacheStore,
+    req_buffer: &mut BytePacketBuffer,
+    len: usize,
+    src: SocketAddr,
+) -> anyhow::Result<DnsPacket> {
+    // Without a whole header there isn't even an ID to answer to.
+    if len < 12 {
+        return Err(BytePacketBufferError::EndOfBuffer.into());
+    }
+
+    let mut header_bytes = [0; 12];
+    header_bytes.copy_from_slice(&req_buffer.buffer[..12]);
+    let req_header = DnsHeader::from_bytes(&header_bytes);
+    let _tag = QueryTagGuard::set(format!("id {} from {}", req_header.id, src));
+
+    // Stop parsing at the end of the query instead of running on into the
+    // zeroed rest of the buffer, so a cut-off question is noticed.
+    req_buffer.buffer.truncate(len);
+
+    // Updates lay their sections out differently, so they get their own
+    // parser rather than going through the one for queries.
+    if req_header.opcode == OPCODE_UPDATE && opcode_allowed(context, OPCODE_UPDATE) {
+        return Ok(build_update_response(context, req_buffer, &req_header, src));
+    }
+
+    match DnsPacket::from_buffer(req_buffer) {
+        Ok(req_packet) => Ok(build_response(context, cache, &req_packet, src)),
+        Err(e) => match e.downcast_ref::<BytePacketBufferError>() {
+            Some(
+                BytePacketBufferError::CompressionPointerInQuestion
+                | BytePacketBufferError::EndOfBuffer,
+            ) => {
+                log!("Rejecting query: {:#}", e);
+                let mut res_packet = reply_to(context, &req_header);
+                res_packet.header.rescode = ResultCode::FormErr;
+                Ok(res_packet)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+fn respond(
+    context: &ServerContext,
+    cache: &dyn CacheStore,
+    req_buffer: &mut BytePacketBuffer,
+    len: usize,
+    src: SocketAddr,
+) -> anyhow::Result<BytePacketBuffer> {
+    if let Some(capture) = &context.capture {
+        write_capture_frame(capture, CaptureDirection::Query, &req_buffer.buffer[..len])?;
+    }
+
+    // The response gets as much room as the query had, which is what lets
+    // TCP answers go past the UDP limit.
+    let capacity = req_buffer.buffer.len();
+    let mut res_packet = resolve_request(context, cache, req_buffer, len, src)?;
+
+    if let Some(ResponseHook(hook)) = &context.on_response {
+        hook(&mut res_packet);
+    }
+
+    #[cfg(debug_assertions)]
+    if let Some(delay) = context.response_delay {
+        thread::sleep(delay);
+    }
+
+    if let Some(block) = context.padding_block {
+        res_packet.fit_within(capacity);
+        res_packet.pad_to_block(block, capacity);
+    }
+
+    let mut res_buffer = BytePacketBuffer::with_size(capacity);
+    res_packet.write_truncated(&mut res_buffer)?;
+
+    if let Some(capture) = &context.capture {
+        let data = res_buffer.get_range(0, res_buffer.position)?;
+        write_capture_frame(capture, CaptureDirection::Response, data)?;
+    }
+
+    Ok(res_buffer)
+}
+
+pub fn handle_query(
+    socket: &UdpSocket,
+    context: &ServerContext,
+    cache: &dyn CacheStore,
+) -> anyhow::Result<()> {
+    let mut req_buffer = BytePacketBuffer::new();
+    req_buffer.lowercase_names = !context.preserve_name_case;
+    req_buffer.reject_question_pointers = context.strict_question_names;
+    let (len, src) = socket.recv_from(&mut req_buffer.buffer)?;
+
+    let mut res_buffer = respond(context, cache, &mut req_buffer, len, src)?;
+    let data = res_buffer.get_range(0, res_buffer.position)?;
+    send_all(socket, data, src)?;
+
+    Ok(())
+}
+
+pub fn handle_query_tcp(
+    stream: &mut TcpStream,
+    context: &ServerContext,
+    cache: &dyn CacheStore,
+) -> anyhow::Result<()> {
+    let src = stream.peer_addr()?;
+
+    let limit = context
+        .max_tcp_connections
+        .unwrap_or(DEFAULT_MAX_TCP_CONNECTIONS);
+    let _connection = match context.tcp_connections.acquire(limit, false) {
+        Some(connection) => connection,
+        None => {
+            log!("Closing TCP connection from {}, too many are open", src);
+            return Ok(());
+        }
+    };
+
+    // Otherwise a client that goes quiet, or stops reading, holds on to its
+    // connection slot and thread indefinitely.
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_IDLE_TIMEOUT))?;
+
+    loop {
+        let mut len_bytes = [0; 2];
+        match stream.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                log!("Closing idle TCP connection from {}", src);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let len = u16::from_be_bytes(len_bytes) as usize;
+
+        let mut req_buffer = BytePacketBuffer::with_size(MAX_TCP_MESSAGE_SIZE);
+        req_buffer.lowercase_names = !context.preserve_name_case;
+        req_buffer.reject_question_pointers = context.strict_question_names;
+        stream.read_exact(&mut req_buffer.buffer[..len])?;
+
+        let mut res_buffer = respond(context, cache, &mut req_buffer, len, src)?;
+        let data = res_buffer.get_range(0, res_buffer.position)?;
+
+        let mut message = (data.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(data);
+        stream.write_all(&message)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn to_bytes(packet: &mut DnsPacket) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::with_size(MAX_TCP_MESSAGE_SIZE);
+        packet.write(&mut buffer).unwrap();
+        buffer.buffer[..buffer.position].to_vec()
+    }
+
+    fn parse(data: &[u8]) -> DnsPacket {
+        DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(data).unwrap()).unwrap()
+    }
+
+    /// Parses a query the way a server echoing its question would, keeping
+    /// the case of the name.
+    fn parse_request(data: &[u8]) -> DnsPacket {
+        let mut buffer = BytePacketBuffer::from_bytes(data).unwrap();
+        buffer.lowercase_names = false;
+        DnsPacket::from_buffer(&mut buffer).unwrap()
+    }
+
+    fn a_record(domain: &str, addr: [u8; 4], ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: Ipv4Addr::from(addr),
+            ttl,
+        }
+    }
+
+    /// Answers every UDP query sent to the returned port on 127.0.0.1 with
+    /// the bytes `respond` makes for it.
+    fn mock_upstream<F>(respond: F) -> u16
+    where
+        F: Fn(&DnsPacket) -> Vec<u8> + Send + 'static,
+    {
+        mock_upstream_at((Ipv4Addr::LOCALHOST, 0), respond)
+    }
+
+    fn mock_upstream_at<F>(addr: (Ipv4Addr, u16), respond: F) -> u16
+    where
+        F: Fn(&DnsPacket) -> Vec<u8> + Send + 'static,
+    {
+        let socket = UdpSocket::bind(addr).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        thread::spawn(move || loop {
+            let mut data = [0; MAX_BUFFER_SIZE];
+            let (len, src) = socket.recv_from(&mut data).unwrap();
+            let request = parse_request(&data[..len]);
+            socket.send_to(&respond(&request), src).unwrap();
+        });
+
+        port
+    }
+
+    fn answer(request: &DnsPacket, answers: Vec<DnsRecord>) -> DnsPacket {
+        let mut response = request.into_response();
+        response.answers = answers;
+        response
+    }
+
+    fn test_config() -> ResolverConfig {
+        ResolverConfig {
+            timeout: Duration::from_millis(500),
+            ..ResolverConfig::default()
+        }
+    }
+
+    #[test]
+    fn response_cut_off_in_rdata_is_truncated() {
+        let question = DnsQuestion::new("example.com".to_string(), QueryType::A);
+        let mut response = answer(
+            &DnsPacket::new().with_question(question),
+            vec![a_record("example.com", [192, 0, 2, 1], 60)],
+        );
+        let data = to_bytes(&mut response);
+
+        let packet = parse(&data[..data.len() - 2]);
+        assert!(packet.header.truncated_message);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn lookup_once_flags_cut_off_responses_and_they_are_not_cached() {
+        let port = mock_upstream(|request| {
+            let mut response = answer(request, vec![a_record("example.com", [192, 0, 2, 1], 60)]);
+            let data = to_bytes(&mut response);
+            data[..data.len() - 3].to_vec()
+        });
+
+        let packet = lookup_once(
+            "example.com",
+            QueryType::A,
+            (Ipv4Addr::LOCALHOST, port),
+            false,
+            &test_config(),
+        )
+        .unwrap();
+        assert!(packet.header.truncated_message);
+        assert!(packet.answers.is_empty());
+        assert_eq!(cache_ttl(&packet), None);
+    }
+
+    #[test]
+    fn lookup_once_retries_truncated_responses_over_tcp() {
+        let port = mock_upstream(|request| {
+            let mut response = request.into_response();
+            response.header.truncated_message = true;
+            to_bytes(&mut response)
+        });
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut req_buffer = read_tcp_message(&mut stream).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+            let mut response = answer(&request, vec![a_record("example.com", [192, 0, 2, 1], 60)]);
+            write_tcp_message(&mut stream, &to_bytes(&mut response)).unwrap();
+        });
+
+        let packet = lookup_once(
+            "example.com",
+            QueryType::A,
+            (Ipv4Addr::LOCALHOST, port),
+            false,
+            &test_config(),
+        )
+        .unwrap();
+        assert!(!packet.header.truncated_message);
+        assert_eq!(packet.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+    }
+
+    #[test]
+    fn forged_responses_are_skipped_with_spoofing_protection() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut data = [0; MAX_BUFFER_SIZE];
+            let (len, src) = socket.recv_from(&mut data).unwrap();
+            let request = parse_request(&data[..len]);
+
+            let mut response = answer(&request, vec![a_record("example.com", [192, 0, 2, 1], 60)]);
+            let mut forged = response.clone();
+            forged.header.id = request.header.id.wrapping_add(1);
+            forged.answers = vec![a_record("example.com", [203, 0, 113, 1], 60)];
+            socket.send_to(&to_bytes(&mut forged), src).unwrap();
+            socket.send_to(&to_bytes(&mut response), src).unwrap();
+        });
+
+        let config = ResolverConfig {
+            spoofing_protection: true,
+            ..test_config()
+        };
+        let packet = lookup_once(
+            "example.com",
+            QueryType::A,
+            (Ipv4Addr::LOCALHOST, port),
+            false,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(packet.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+    }
+
+    fn soa_record(origin: &str, serial: u32) -> DnsRecord {
+        DnsRecord::Soa {
+            domain: origin.to_string(),
+            m_name: format!("ns.{}", origin),
+            r_name: format!("hostmaster.{}", origin),
+            serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    fn update_context() -> ServerContext {
+        let context = ServerContext {
+            allowed_opcodes: Some(vec![OPCODE_QUERY, OPCODE_UPDATE]),
+            update_sources: vec!["127.0.0.0/8".parse().unwrap()],
+            ..ServerContext::default()
+        };
+        let mut zone = Zone::new("example.com", soa_record("example.com", 1));
+        zone.records
+            .push(a_record("www.example.com", [192, 0, 2, 1], 60));
+        context.zones.write().unwrap().push(zone);
+        context
+    }
+
+    fn update_message(updates: Vec<DnsRecord>) -> Vec<u8> {
+        let mut request = DnsPacket::new()
+            .with_id(7)
+            .with_question(DnsQuestion::new("example.com".to_string(), QueryType::Soa));
+        request.header.opcode = OPCODE_UPDATE;
+        request.authorities = updates;
+        to_bytes(&mut request)
+    }
+
+    fn send_update(context: &ServerContext, data: &[u8], src: &str) -> DnsPacket {
+        let mut req_buffer = BytePacketBuffer::from_bytes(data).unwrap();
+        let src = src.parse().unwrap();
+        resolve_request(context, &Cache::new(), &mut req_buffer, data.len(), src).unwrap()
+    }
+
+    fn zone_serial(context: &ServerContext) -> u32 {
+        match context.zones.read().unwrap()[0].soa {
+            DnsRecord::Soa { serial, .. } => serial,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn updates_from_allowed_sources_are_applied_and_bump_the_serial() {
+        let context = update_context();
+        let added = a_record("mail.example.com", [192, 0, 2, 25], 60);
+
+        let response = send_update(
+            &context,
+            &update_message(vec![added.clone()]),
+            "127.0.0.1:5300",
+        );
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert!(context.zones.read().unwrap()[0].records.contains(&added));
+        assert_eq!(zone_serial(&context), 2);
+    }
+
+    #[test]
+    fn updates_from_other_sources_are_refused() {
+        let context = update_context();
+        let added = a_record("mail.example.com", [192, 0, 2, 25], 60);
+
+        let response = send_update(
+            &context,
+            &update_message(vec![added.clone()]),
+            "192.0.2.9:5300",
+        );
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+        assert!(!context.zones.read().unwrap()[0].records.contains(&added));
+        assert_eq!(zone_serial(&context), 1);
+    }
+
+    #[test]
+    fn delete_rrset_updates_parse_and_apply_with_the_ops_after_them() {
+        let context = update_context();
+        let added = a_record("mail.example.com", [192, 0, 2, 25], 60);
+
+        let mut data = update_message(Vec::new());
+        let mut updates = BytePacketBuffer::with_size(MAX_BUFFER_SIZE);
+        updates.write_qname("www.example.com").unwrap();
+        updates.write_u16(QueryType::A.into()).unwrap();
+        updates.write_u16(QueryClass::Any.into()).unwrap();
+        updates.write_u32(0).unwrap();
+        updates.write_u16(0).unwrap();
+        added.write(&mut updates, QueryClass::In).unwrap();
+        data.extend_from_slice(&updates.buffer[..updates.position]);
+        data[8..10].copy_from_slice(&2u16.to_be_bytes());
+
+        let response = send_update(&context, &data, "127.0.0.1:5300");
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(context.zones.read().unwrap()[0].records, vec![added]);
+
+        // The generic parser reads the empty rdata too.
+        let packet = parse(&data);
+        assert_eq!(packet.authorities.len(), 2);
+    }
+
+    struct PrefixCodec;
+
+    impl RecordCodec for PrefixCodec {
+        fn read(&self, buffer: &mut BytePacketBuffer, _data_len: u16) -> anyhow::Result<Vec<u8>> {
+            buffer.read_bytes(2)
+        }
+
+        fn write(&self, data: &[u8], buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
+            buffer.write_bytes(data)
+        }
+    }
+
+    #[test]
+    fn codecs_reading_less_than_the_rdata_skip_the_rest() {
+        register_record_codec(65280, PrefixCodec).unwrap();
+
+        let mut buffer = BytePacketBuffer::with_size(MAX_BUFFER_SIZE);
+        for domain in ["a.example", "b.example"] {
+            buffer.write_qname(domain).unwrap();
+            buffer.write_u16(65280).unwrap();
+            buffer.write_u16(1).unwrap();
+            buffer.write_u32(60).unwrap();
+            buffer.write_u16(4).unwrap();
+            buffer.write_bytes(&[1, 2, 3, 4]).unwrap();
+        }
+        buffer.seek(0).unwrap();
+
+        let first = DnsRecord::read(&mut buffer).unwrap();
+        let second = DnsRecord::read(&mut buffer).unwrap();
+        assert_eq!(first.domain(), "a.example");
+        assert_eq!(second.domain(), "b.example");
+        assert!(matches!(second, DnsRecord::Unknown { ref data, .. } if data == &[1, 2]));
+    }
+
+    fn view_context() -> ServerContext {
+        let name = "intranet.example".to_string();
+        ServerContext {
+            hosts: Hosts::from([(name.clone(), vec![IpAddr::from([203, 0, 113, 5])])]),
+            views: vec![View {
+                subnet: "10.0.0.0/8".parse().unwrap(),
+                hosts: Hosts::from([(name, vec![IpAddr::from([10, 0, 0, 5])])]),
+            }],
+            trusted_ecs_sources: vec!["192.0.2.53/32".parse().unwrap()],
+            ..ServerContext::default()
+        }
+    }
+
+    fn query_with_subnet(subnet: &str) -> DnsPacket {
+        let mut request = DnsPacket::new().with_id(1).with_question(DnsQuestion::new(
+            "intranet.example".to_string(),
+            QueryType::A,
+        ));
+        request.add_edns_option(EdnsOption::client_subnet(subnet.parse().unwrap(), 0));
+        request
+    }
+
+    #[test]
+    fn client_subnet_options_only_pick_views_for_trusted_forwarders() {
+        let context = view_context();
+        let request = query_with_subnet("10.1.2.0/24");
+
+        let src = "198.51.100.7:5300".parse().unwrap();
+        let response = build_response(&context, &Cache::new(), &request, src);
+        assert_eq!(response.addresses(), vec![IpAddr::from([203, 0, 113, 5])]);
+        assert!(!response.has_edns_option(EDNS_OPTION_CLIENT_SUBNET));
+
+        let src = "192.0.2.53:5300".parse().unwrap();
+        let response = build_response(&context, &Cache::new(), &request, src);
+        assert_eq!(response.addresses(), vec![IpAddr::from([10, 0, 0, 5])]);
+        assert_eq!(
+            response.client_subnet(),
+            Some("10.1.2.0/24".parse().unwrap())
+        );
+        assert!(matches!(
+            response.opt(),
+            Some(DnsRecord::Opt { options, .. })
+                if options.contains(&EdnsOption::client_subnet("10.1.2.0/24".parse().unwrap(), 24))
+        ));
+    }
+
+    #[test]
+    fn chaos_answers_are_written_in_class_ch() {
+        let context = ServerContext {
+            chaos_version: Some("toy 1".to_string()),
+            ..ServerContext::default()
+        };
+        let mut question = DnsQuestion::new("version.bind".to_string(), QueryType::Txt);
+        question.qclass = QueryClass::Ch;
+        let request = DnsPacket::new().with_id(1).with_question(question);
+
+        let src = "127.0.0.1:5300".parse().unwrap();
+        let mut response = build_response(&context, &Cache::new(), &request, src);
+        let mut buffer = BytePacketBuffer::from_bytes(&to_bytes(&mut response)).unwrap();
+
+        let mut header = DnsHeader::new();
+        header.read(&mut buffer).unwrap();
+        let mut echoed = DnsQuestion::new(String::new(), QueryType::Unknown(0));
+        echoed.read(&mut buffer).unwrap();
+        let mut domain = String::new();
+        buffer.read_qname(&mut domain).unwrap();
+        assert_eq!(buffer.read_u16().unwrap(), u16::from(QueryType::Txt));
+        assert_eq!(QueryClass::from(buffer.read_u16().unwrap()), QueryClass::Ch);
+    }
+
+    #[test]
+    fn escaped_dots_stay_inside_their_label() {
+        assert_eq!(normalize_name("A\\.B.Example.").unwrap(), "a\\.b.example");
+        assert_eq!(normalize_name("\\065bc.com").unwrap(), "abc.com");
+        assert_eq!(label_count("a\\.b.example"), 2);
+
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("a\\.b.example").unwrap();
+        assert_eq!(&buffer.buffer[..13], b"\x03a.b\x07example\x00");
+
+        buffer.seek(0).unwrap();
+        let mut name = String::new();
+        buffer.read_qname(&mut name).unwrap();
+        assert_eq!(name, "a\\.b.example");
+    }
+
+    #[test]
+    fn invalid_escapes_are_rejected() {
+        assert!(normalize_name("a\\256.example").is_err());
+        assert!(normalize_name("a\\1.example").is_err());
+        assert!(normalize_name("example\\").is_err());
+    }
+
+    #[test]
+    fn tcp_connections_past_the_limit_are_closed() {
+        let context = ServerContext {
+            max_tcp_connections: Some(0),
+            ..ServerContext::default()
+        };
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        handle_query_tcp(&mut stream, &context, &Cache::new()).unwrap();
+        drop(stream);
+
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn waiters_get_an_error_when_the_leader_panics() {
+        let flight = SingleFlight::default();
+        let key = ("example.com".to_string(), QueryType::A);
+
+        thread::scope(|scope| {
+            let leader = scope.spawn(|| {
+                flight.run(key.clone(), || {
+                    thread::sleep(Duration::from_millis(200));
+                    panic!("lookup failed");
+                })
+            });
+            thread::sleep(Duration::from_millis(50));
+
+            let result = flight.run(key.clone(), || Ok(DnsPacket::new()));
+            assert!(result.is_err());
+            assert!(leader.join().is_err());
+        });
+        assert!(flight.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_nameserver_lookups_share_the_cache_and_query_context() {
+        let referred = AtomicBool::new(false);
+        let port = mock_upstream(move |request| {
+            let name = request.questions[0].name.to_lowercase();
+            let mut response = match name.as_str() {
+                "ns1.example.net" | "ns2.example.net" => {
+                    answer(request, vec![a_record(&name, [127, 0, 0, 1], 300)])
+                }
+                _ if !referred.swap(true, Ordering::SeqCst) => {
+                    let mut response = request.into_response();
+                    for host in ["ns1.example.net", "ns2.example.net"] {
+                        response.authorities.push(DnsRecord::Ns {
+                            domain: "example.com".to_string(),
+                            host: host.to_string(),
+                            ttl: 300,
+                        });
+                    }
+                    response
+                }
+                _ => answer(request, vec![a_record(&name, [192, 0, 2, 1], 300)]),
+            };
+            to_bytes(&mut response)
+        });
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ns_concurrency: 2,
+            ..test_config()
+        };
+        let cache = Cache::new();
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let query = QueryContext::new(1, question);
+
+        let response = recursive_lookup(
+            "www.example.com",
+            QueryType::A,
+            &config,
+            &RttTracker::new(),
+            &cache,
+            &query,
+        )
+        .unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert!(cache.get("ns1.example.net", QueryType::A).is_some());
+        assert!(cache.get("ns2.example.net", QueryType::A).is_some());
+        assert_eq!(query.servers().len(), 4);
+    }
+
+    #[test]
+    fn referrals_list_every_nameserver_in_the_zone() {
+        let mut referral = DnsPacket::new();
+        for (zone, host) in [
+            ("example.com", "ns1.example.net"),
+            ("example.org", "ns.example.org"),
+            ("example.com", "ns2.example.net"),
+        ] {
+            referral.authorities.push(DnsRecord::Ns {
+                domain: zone.to_string(),
+                host: host.to_string(),
+                ttl: 300,
+            });
+        }
+
+        assert_eq!(
+            referral.get_unresolved_ns_all("www.example.com"),
+            vec!["ns1.example.net", "ns2.example.net"]
+        );
+        assert_eq!(
+            referral.get_unresolved_ns("www.example.com"),
+            Some("ns1.example.net")
+        );
+    }
+
+    #[test]
+    fn nxdomain_for_puts_the_soa_in_the_authority_section() {
+        let question = DnsQuestion::new("missing.example.com".to_string(), QueryType::A);
+        let soa = soa_record("example.com", 1);
+
+        let packet = DnsPacket::nxdomain_for(question.clone(), soa.clone());
+        assert_eq!(packet.header.rescode, ResultCode::NxDomain);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.questions, vec![question]);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities, vec![soa]);
+    }
+
+    fn ask(context: &ServerContext, name: &str, qtype: QueryType) -> DnsPacket {
+        let request = DnsPacket::new()
+            .with_id(1)
+            .with_question(DnsQuestion::new(name.to_string(), qtype));
+        let src = "127.0.0.1:5300".parse().unwrap();
+        build_response(context, &Cache::new(), &request, src)
+    }
+
+    #[test]
+    fn hosts_files_answer_matching_names() {
+        let hosts = parse_hosts(
+            "# local overrides\n\
+             192.0.2.10  printer.lan  Printer.Home.  # two names\n\
+             2001:db8::10 printer.lan\n\
+             not-an-address ignored.lan\n",
+        );
+        assert_eq!(hosts.len(), 2);
+        assert!(!hosts.contains_key("ignored.lan"));
+
+        let context = ServerContext {
+            hosts,
+            ..ServerContext::default()
+        };
+        let response = ask(&context, "printer.home", QueryType::A);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 10])]);
+
+        let response = ask(&context, "printer.lan", QueryType::Aaaa);
+        assert_eq!(
+            response.addresses(),
+            vec!["2001:db8::10".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn captures_hold_the_exact_query_and_response_bytes() {
+        let path = std::env::temp_dir().join(format!("capture-{}.bin", std::process::id()));
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            capture: Some(File::create(&path).unwrap()),
+            ..ServerContext::default()
+        };
+        let query = to_bytes(
+            &mut DnsPacket::new()
+                .with_id(3)
+                .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A)),
+        );
+
+        let response = serve_udp(&context, &query);
+        assert_eq!(
+            parse(&response).addresses(),
+            vec![IpAddr::from([192, 0, 2, 10])]
+        );
+
+        let frames = read_capture(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].direction, CaptureDirection::Query);
+        assert_eq!(frames[0].data, query);
+        assert_eq!(frames[1].direction, CaptureDirection::Response);
+        assert_eq!(frames[1].data, response);
+        assert!(frames[0].timestamp <= frames[1].timestamp);
+    }
+
+    fn referral(request: &DnsPacket, zone: &str, host: &str) -> DnsPacket {
+        let mut response = request.into_response();
+        response.authorities.push(DnsRecord::Ns {
+            domain: zone.to_string(),
+            host: host.to_string(),
+            ttl: 300,
+        });
+        response
+    }
+
+    #[test]
+    fn deep_glueless_delegation_chains_resolve() {
+        const DEPTH: usize = 40;
+
+        // Every name is referred on the first ask and answered after that.
+        // The nameserver for each zone lives in the next one down the chain,
+        // and only the last can be looked up straight away.
+        let seen = Mutex::new(HashSet::new());
+        let port = mock_upstream(move |request| {
+            let name = request.questions[0].name.to_lowercase();
+            let first = seen.lock().unwrap().insert(name.clone());
+            let level = name
+                .strip_prefix("ns.d")
+                .and_then(|rest| rest.strip_suffix(".test"))
+                .and_then(|level| level.parse::<usize>().ok());
+            let mut response = match (first, level) {
+                (true, None) => referral(request, "example.com", "ns.d1.test"),
+                (true, Some(level)) if level < DEPTH => referral(
+                    request,
+                    &format!("d{}.test", level),
+                    &format!("ns.d{}.test", level + 1),
+                ),
+                (_, Some(_)) => answer(request, vec![a_record(&name, [127, 0, 0, 1], 300)]),
+                (false, None) => answer(request, vec![a_record(&name, [192, 0, 2, 1], 300)]),
+            };
+            to_bytes(&mut response)
+        });
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+
+        // Run on a small stack, the lookup shouldn't need more for a deep chain.
+        let response = thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || {
+                let query = QueryContext::new(1, question);
+                let cache = Cache::new();
+                recursive_lookup(
+                    "www.example.com",
+                    QueryType::A,
+                    &config,
+                    &RttTracker::new(),
+                    &cache,
+                    &query,
+                )
+            })
+            .unwrap()
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+
+    #[test]
+    fn mutually_glueless_delegations_are_given_up_on() {
+        // Each zone's nameserver lives in the other zone, and neither comes
+        // with glue, so neither address can ever be found.
+        let port = mock_upstream(|request| {
+            let name = request.questions[0].name.to_lowercase();
+            let mut response = if name.ends_with("example.com") {
+                referral(request, "example.com", "ns.example.org")
+            } else {
+                referral(request, "example.org", "ns.example.com")
+            };
+            to_bytes(&mut response)
+        });
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let query = QueryContext::new(1, question);
+
+        let response = lookup(&query, &config).unwrap();
+        assert!(response.answers.is_empty());
+        assert_eq!(query.servers().len(), 3);
+    }
+
+    #[test]
+    fn questions_match_records_by_name_and_type() {
+        let question = DnsQuestion::new("WWW.example.com".to_string(), QueryType::A);
+        let cname = DnsRecord::Cname {
+            domain: "www.example.com".to_string(),
+            host: "web.example.com".to_string(),
+            ttl: 60,
+        };
+        let mx = DnsRecord::Mx {
+            domain: "www.example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: 60,
+        };
+
+        assert!(question.matches(&a_record("www.example.com", [192, 0, 2, 1], 60)));
+        assert!(question.matches(&cname));
+        assert!(!question.matches(&mx));
+        assert!(!question.matches(&a_record("example.com", [192, 0, 2, 1], 60)));
+
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::Mx);
+        assert!(question.matches(&mx));
+        assert!(!question.matches(&cname));
+    }
+
+    #[test]
+    fn names_keep_their_case_when_asked_to() {
+        let data = to_bytes(&mut DnsPacket::new().with_question(DnsQuestion::new(
+            "WwW.ExAmPlE.com".to_string(),
+            QueryType::A,
+        )));
+
+        assert_eq!(parse(&data).questions[0].name, "www.example.com");
+        assert_eq!(parse_request(&data).questions[0].name, "WwW.ExAmPlE.com");
+    }
+
+    #[test]
+    fn records_outside_the_delegated_zone_are_stripped() {
+        let referred = AtomicBool::new(false);
+        let port = mock_upstream(move |request| {
+            let name = request.questions[0].name.to_lowercase();
+            let mut response = if !referred.swap(true, Ordering::SeqCst) {
+                let mut response = referral(request, "example.com", "ns.example.com");
+                response
+                    .resources
+                    .push(a_record("ns.example.com", [127, 0, 0, 1], 300));
+                response
+            } else {
+                let mut response = answer(
+                    request,
+                    vec![
+                        a_record(&name, [192, 0, 2, 1], 300),
+                        a_record("bank.test", [203, 0, 113, 66], 300),
+                    ],
+                );
+                response
+                    .resources
+                    .push(a_record("ns.bank.test", [203, 0, 113, 66], 300));
+                response
+            };
+            to_bytes(&mut response)
+        });
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+        let cache = Cache::new();
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+
+        let response = recursive_lookup(
+            "www.example.com",
+            QueryType::A,
+            &config,
+            &RttTracker::new(),
+            &cache,
+            &QueryContext::new(1, question),
+        )
+        .unwrap();
+        assert_eq!(
+            response.answers,
+            vec![a_record("www.example.com", [192, 0, 2, 1], 300)]
+        );
+        assert!(response.resources.is_empty());
+        assert!(cache.get("bank.test", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn query_types_round_trip_through_their_names() {
+        for name in ["A", "AAAA", "MX", "NS", "CNAME", "TXT", "SOA"] {
+            let qtype: QueryType = name.parse().unwrap();
+            assert_eq!(qtype.to_string(), name);
+        }
+
+        assert_eq!("aaaa".parse::<QueryType>().unwrap(), QueryType::Aaaa);
+        assert_eq!("Mx".parse::<QueryType>().unwrap(), QueryType::Mx);
+        assert_eq!(
+            "TYPE65".parse::<QueryType>().unwrap(),
+            QueryType::Unknown(65)
+        );
+        assert_eq!("type1".parse::<QueryType>().unwrap(), QueryType::A);
+        assert_eq!(QueryType::Unknown(65).to_string(), "TYPE65");
+        assert!("BOGUS".parse::<QueryType>().is_err());
+        assert!("TYPE70000".parse::<QueryType>().is_err());
+    }
+
+    #[test]
+    fn responses_with_impossible_section_counts_are_rejected() {
+        let mut header = vec![0; 12];
+        header[6..8].copy_from_slice(&u16::MAX.to_be_bytes());
+        let port = {
+            let header = header.clone();
+            mock_upstream(move |request| {
+                let mut data = header.clone();
+                data[..2].copy_from_slice(&request.header.id.to_be_bytes());
+                data
+            })
+        };
+
+        assert!(DnsPacket::check_section_counts(&header).is_err());
+        assert!(DnsPacket::check_section_counts(&header[..6]).is_err());
+        assert!(DnsPacket::check_section_counts(&to_bytes(&mut DnsPacket::new())).is_ok());
+
+        let err = lookup_once(
+            "example.com",
+            QueryType::A,
+            (Ipv4Addr::LOCALHOST, port),
+            false,
+            &test_config(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DnsPacketError>(),
+            Some(DnsPacketError::SectionCountsExceedLength(12))
+        ));
+    }
+
+    #[test]
+    fn localhost_is_answered_locally() {
+        let context = ServerContext::default();
+
+        let response = ask(&context, "localhost", QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(
+            response.addresses(),
+            vec![IpAddr::from(Ipv4Addr::LOCALHOST)]
+        );
+
+        let response = ask(&context, "app.localhost", QueryType::Aaaa);
+        assert_eq!(
+            response.addresses(),
+            vec![IpAddr::from(Ipv6Addr::LOCALHOST)]
+        );
+
+        let context = ServerContext {
+            answer_local: true,
+            ..ServerContext::default()
+        };
+        let response = ask(&context, "printer.local", QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::NxDomain);
+    }
+
+    #[test]
+    fn responses_mirror_the_request_header() {
+        let mut request = DnsPacket::new()
+            .with_id(4242)
+            .with_question(DnsQuestion::new("example.com".to_string(), QueryType::Mx))
+            .with_recursion_desired(true);
+        request.header.checking_disabled = true;
+
+        let response = request.into_response();
+        assert_eq!(response.header.id, 4242);
+        assert!(response.header.response);
+        assert!(response.header.recursion_desired);
+        assert!(response.header.recursion_available);
+        assert!(response.header.checking_disabled);
+        assert_eq!(response.header.opcode, OPCODE_QUERY);
+        assert_eq!(response.questions, request.questions);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn extended_errors_are_written_into_the_opt_record() {
+        let mut response = DnsPacket::new();
+        response.header.rescode = ResultCode::ServFail;
+        response.add_extended_error(22, "no reachable authority");
+
+        let data = to_bytes(&mut response);
+        let mut expected = 22u16.to_be_bytes().to_vec();
+        expected.extend_from_slice(b"no reachable authority");
+        match parse(&data).opt() {
+            Some(DnsRecord::Opt { options, .. }) => assert_eq!(
+                options,
+                &vec![EdnsOption {
+                    code: EDNS_OPTION_EXTENDED_ERROR,
+                    data: expected,
+                }]
+            ),
+            other => panic!("expected an OPT record, got {:?}", other),
+        }
+    }
+
+    fn round_trip(record: &DnsRecord) -> DnsRecord {
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer, QueryClass::In).unwrap();
+        buffer.seek(0).unwrap();
+        DnsRecord::read(&mut buffer).unwrap()
+    }
+
+    #[test]
+    fn sshfp_records_round_trip() {
+        let record = DnsRecord::Sshfp {
+            domain: "host.example.com".to_string(),
+            algorithm: 4,
+            fp_type: 2,
+            fingerprint: (0..32).collect(),
+            ttl: 3600,
+        };
+        assert_eq!(round_trip(&record), record);
+    }
+
+    #[test]
+    fn dig_strings_show_every_section() {
+        let question = DnsQuestion::new("example.com".to_string(), QueryType::A);
+        let mut response = answer(
+            &DnsPacket::new().with_id(99).with_question(question),
+            vec![a_record("example.com", [192, 0, 2, 1], 300)],
+        );
+        response.authorities.push(DnsRecord::Ns {
+            domain: "example.com".to_string(),
+            host: "ns.example.com".to_string(),
+            ttl: 300,
+        });
+
+        let out = response.to_dig_string();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines.contains(&";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 99"));
+        assert!(
+            lines.contains(&";; flags: qr ra; QUERY: 1, ANSWER: 1, AUTHORITY: 1, ADDITIONAL: 0")
+        );
+        assert!(lines.contains(&";example.com.\t\tIN\tA"));
+        assert!(lines.contains(&"example.com.\t300\tIN\tA\t192.0.2.1"));
+        assert!(lines.contains(&"example.com.\t300\tIN\tNS\tns.example.com."));
+        assert!(!out.contains("ADDITIONAL SECTION"));
+    }
+
+    fn glued_referral() -> DnsPacket {
+        let mut response = DnsPacket::new();
+        for (host, addr) in [("a.ns.test", 1), ("b.ns.test", 2), ("c.ns.test", 3)] {
+            response.authorities.push(DnsRecord::Ns {
+                domain: "example.com".to_string(),
+                host: host.to_string(),
+                ttl: 300,
+            });
+            response
+                .resources
+                .push(a_record(host, [192, 0, 2, addr], 300));
+        }
+        response
+    }
+
+    #[test]
+    fn nameservers_are_picked_by_the_configured_strategy() {
+        let response = glued_referral();
+        let rtts = RttTracker::new();
+        let pick = |selection| {
+            response
+                .get_resolved_ns("www.example.com", selection, &rtts)
+                .unwrap()
+        };
+
+        assert_eq!(pick(NsSelection::First), Ipv4Addr::new(192, 0, 2, 1));
+
+        let picked: HashSet<Ipv4Addr> = (0..100).map(|_| pick(NsSelection::Random)).collect();
+        assert!(picked.len() > 1);
+
+        for (addr, millis) in [(1, 80), (2, 5), (3, 40)] {
+            rtts.record(
+                Ipv4Addr::new(192, 0, 2, addr),
+                Duration::from_millis(millis),
+            );
+        }
+        assert_eq!(pick(NsSelection::Fastest), Ipv4Addr::new(192, 0, 2, 2));
+    }
+
+    #[test]
+    fn queries_over_ipv6_loopback_are_answered() {
+        let port = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let sockets = bind_sockets(port).unwrap();
+        let socket = sockets
+            .iter()
+            .find(|socket| socket.local_addr().unwrap().is_ipv6());
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            ..ServerContext::default()
+        };
+
+        let client = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = to_bytes(
+            &mut DnsPacket::new()
+                .with_id(6)
+                .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A)),
+        );
+
+        let mut data = [0; MAX_BUFFER_SIZE];
+        let len = thread::scope(|scope| {
+            scope.spawn(|| handle_query(socket.unwrap(), &context, &Cache::new()).unwrap());
+            client.send_to(&query, (Ipv6Addr::LOCALHOST, port)).unwrap();
+            client.recv(&mut data).unwrap()
+        });
+
+        let response = parse(&data[..len]);
+        assert_eq!(response.header.id, 6);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 10])]);
+    }
+
+    fn answer_packet(domain: &str, addr: [u8; 4]) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(a_record(domain, addr, 300));
+        packet
+    }
+
+    #[test]
+    fn the_in_memory_cache_works_through_the_trait() {
+        let cache = Cache::new();
+        let store: &dyn CacheStore = &cache;
+        let packet = answer_packet("example.com", [192, 0, 2, 1]);
+
+        store.put("Example.com", QueryType::A, packet.clone(), 300);
+        let cached = store.get("example.COM", QueryType::A).unwrap();
+        assert_eq!(cached.answers, packet.answers);
+        assert!(store.get("example.com", QueryType::Aaaa).is_none());
+        assert_eq!(store.cached_types("example.com"), vec![QueryType::A]);
+
+        store.remove("example.com", QueryType::A);
+        assert!(store.get("example.com", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn full_caches_evict_the_least_recently_used_entry() {
+        let cache = Cache::with_capacity(2);
+        cache.put(
+            "a.test",
+            QueryType::A,
+            answer_packet("a.test", [192, 0, 2, 1]),
+            300,
+        );
+        thread::sleep(Duration::from_millis(2));
+        cache.put(
+            "b.test",
+            QueryType::A,
+            answer_packet("b.test", [192, 0, 2, 2]),
+            300,
+        );
+        thread::sleep(Duration::from_millis(2));
+        assert!(cache.get("a.test", QueryType::A).is_some());
+
+        cache.put(
+            "c.test",
+            QueryType::A,
+            answer_packet("c.test", [192, 0, 2, 3]),
+            300,
+        );
+        assert_eq!(cache.entries().len(), 2);
+        assert!(cache.get("a.test", QueryType::A).is_some());
+        assert!(cache.get("b.test", QueryType::A).is_none());
+        assert!(cache.get("c.test", QueryType::A).is_some());
+
+        let disabled = Cache::with_capacity(0);
+        disabled.put(
+            "a.test",
+            QueryType::A,
+            answer_packet("a.test", [192, 0, 2, 1]),
+            300,
+        );
+        assert!(disabled.get("a.test", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn only_zone_answers_are_authoritative() {
+        let context = update_context();
+        let response = ask(&context, "www.example.com", QueryType::A);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+
+        let port = mock_upstream(|request| {
+            let name = request.questions[0].name.clone();
+            let mut response = answer(request, vec![a_record(&name, [198, 51, 100, 1], 300)]);
+            response.header.authoritative_answer = true;
+            to_bytes(&mut response)
+        });
+        let context = ServerContext {
+            forwarders: vec![(Ipv4Addr::LOCALHOST, port)],
+            resolver: test_config(),
+            ..ServerContext::default()
+        };
+        let response = ask(&context, "www.example.org", QueryType::A);
+        assert!(!response.header.authoritative_answer);
+        assert_eq!(response.addresses(), vec![IpAddr::from([198, 51, 100, 1])]);
+    }
+
+    fn slow_forwarder_context(queue_when_busy: bool) -> ServerContext {
+        let port = mock_upstream(|request| {
+            thread::sleep(Duration::from_millis(200));
+            let name = request.questions[0].name.clone();
+            to_bytes(&mut answer(
+                request,
+                vec![a_record(&name, [198, 51, 100, 1], 300)],
+            ))
+        });
+        ServerContext {
+            forwarders: vec![(Ipv4Addr::LOCALHOST, port)],
+            resolver: ResolverConfig {
+                timeout: Duration::from_secs(2),
+                ..test_config()
+            },
+            max_in_flight: Some(1),
+            queue_when_busy,
+            ..ServerContext::default()
+        }
+    }
+
+    #[test]
+    fn lookups_past_the_in_flight_limit_are_refused_or_queued() {
+        let context = slow_forwarder_context(false);
+        thread::scope(|scope| {
+            let first = scope.spawn(|| ask(&context, "a.example.org", QueryType::A));
+            thread::sleep(Duration::from_millis(50));
+            let second = ask(&context, "b.example.org", QueryType::A);
+            assert_eq!(second.header.rescode, ResultCode::Refused);
+            assert_eq!(first.join().unwrap().header.rescode, ResultCode::NoError);
+        });
+
+        let context = slow_forwarder_context(true);
+        thread::scope(|scope| {
+            let first = scope.spawn(|| ask(&context, "a.example.org", QueryType::A));
+            thread::sleep(Duration::from_millis(50));
+            let second = ask(&context, "b.example.org", QueryType::A);
+            assert_eq!(second.header.rescode, ResultCode::NoError);
+            assert_eq!(first.join().unwrap().header.rescode, ResultCode::NoError);
+        });
+    }
+
+    #[test]
+    fn records_differing_only_in_ttl_are_the_same_rr() {
+        let record = a_record("example.com", [192, 0, 2, 1], 60);
+
+        assert!(record.same_rr(&a_record("example.com", [192, 0, 2, 1], 3600)));
+        assert!(!record.same_rr(&a_record("example.com", [192, 0, 2, 2], 60)));
+        assert!(!record.same_rr(&a_record("www.example.com", [192, 0, 2, 1], 60)));
+    }
+
+    #[test]
+    fn stale_answers_are_served_when_upstream_times_out() {
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = silent.local_addr().unwrap().port();
+        let context = ServerContext {
+            forwarders: vec![(Ipv4Addr::LOCALHOST, port)],
+            resolver: ResolverConfig {
+                timeout: Duration::from_millis(100),
+                retries: 0,
+                ..test_config()
+            },
+            serve_stale: Some(300),
+            ..ServerContext::default()
+        };
+        let cache = Cache::new();
+        cache.put(
+            "www.example.org",
+            QueryType::A,
+            answer_packet("www.example.org", [192, 0, 2, 1]),
+            0,
+        );
+
+        let request = DnsPacket::new().with_id(1).with_question(DnsQuestion::new(
+            "www.example.org".to_string(),
+            QueryType::A,
+        ));
+        let src = "127.0.0.1:5300".parse().unwrap();
+        let response = build_response(&context, &cache, &request, src);
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(response.answers[0].ttl(), STALE_TTL);
+
+        let context = ServerContext {
+            serve_stale: None,
+            ..context
+        };
+        let response = build_response(&context, &cache, &request, src);
+        assert_eq!(response.header.rescode, ResultCode::ServFail);
+    }
+
+    #[test]
+    fn dig_arguments_parse_into_a_query() {
+        let query = parse_dig_args(["example.com", "MX", "@8.8.8.8"]).unwrap();
+        assert_eq!(
+            query.question,
+            DnsQuestion::new("example.com".to_string(), QueryType::Mx)
+        );
+        assert_eq!(query.server, Some(IpAddr::from([8, 8, 8, 8])));
+        assert!(query.recursion_desired);
+
+        let query = parse_dig_args(["@2001:db8::53", "+norec", "Example.com."]).unwrap();
+        assert_eq!(query.question.qtype, QueryType::A);
+        assert_eq!(query.question.name, "example.com");
+        assert_eq!(query.server, Some("2001:db8::53".parse().unwrap()));
+        assert!(!query.recursion_desired);
+
+        let query = parse_dig_args(["aaaa", "example.com"]).unwrap();
+        assert_eq!(query.question.qtype, QueryType::Aaaa);
+
+        assert!(matches!(
+            parse_dig_args(["A"]),
+            Err(DigArgsError::MissingName)
+        ));
+        assert!(matches!(
+            parse_dig_args(["example.com", "@dns.google"]),
+            Err(DigArgsError::InvalidServer(_))
+        ));
+        assert!(matches!(
+            parse_dig_args(["example.com", "example.org"]),
+            Err(DigArgsError::UnexpectedArgument(_))
+        ));
+    }
+
+    #[test]
+    fn buffers_account_for_the_bytes_left() {
+        let mut buffer = BytePacketBuffer::from_bytes(&[0, 1, 0, 0, 0, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(buffer.remaining(), 10);
+
+        buffer.read_u16().unwrap();
+        buffer.read_u32().unwrap();
+        assert_eq!(buffer.position(), 6);
+        assert_eq!(buffer.remaining(), 4);
+
+        buffer.step(3).unwrap();
+        assert_eq!(buffer.remaining(), 1);
+        assert!(buffer.step(2).is_err());
+        assert_eq!(buffer.position(), 9);
+
+        buffer.seek(10).unwrap();
+        assert_eq!(buffer.remaining(), 0);
+        assert!(buffer.read().is_err());
+        assert!(buffer.seek(11).is_err());
+    }
+
+    #[test]
+    fn edns_requests_get_edns_responses() {
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            ..ServerContext::default()
+        };
+        let src = "127.0.0.1:5300".parse().unwrap();
+        let mut request = DnsPacket::new()
+            .with_id(1)
+            .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A));
+
+        let response = build_response(&context, &Cache::new(), &request, src);
+        assert!(response.opt().is_none());
+
+        request.resources.push(DnsRecord::Opt {
+            packet_len: 400,
+            flags: EDNS_FLAG_DNSSEC_OK,
+            options: Vec::new(),
+        });
+        let response = parse(&to_bytes(&mut build_response(
+            &context,
+            &Cache::new(),
+            &request,
+            src,
+        )));
+        match response.opt() {
+            Some(DnsRecord::Opt {
+                packet_len, flags, ..
+            }) => {
+                assert_eq!(*packet_len, 400);
+                assert_eq!(*flags, EDNS_FLAG_DNSSEC_OK);
+            }
+            other => panic!("expected an OPT record, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn labels_are_counted() {
+        assert_eq!(label_count("example.com"), 2);
+        assert_eq!(label_count("example.com."), 2);
+        assert_eq!(label_count("a.b.c.example.com"), 5);
+        assert_eq!(label_count("."), 0);
+        assert_eq!(label_count(""), 0);
+        assert_eq!(label_count("a\\.b.example.com"), 3);
+    }
+
+    #[test]
+    fn uri_records_round_trip() {
+        let record = DnsRecord::Uri {
+            domain: "_https._tcp.example.com".to_string(),
+            priority: 10,
+            weight: 1,
+            target: "https://www.example.com/path?q=1".to_string(),
+            ttl: 3600,
+        };
+        assert_eq!(round_trip(&record), record);
+    }
+
+    struct ShortSocket;
+
+    impl DatagramSocket for ShortSocket {
+        fn send_to<A: ToSocketAddrs>(&self, data: &[u8], _addr: A) -> io::Result<usize> {
+            Ok(data.len() - 1)
+        }
+    }
+
+    #[test]
+    fn partial_sends_are_errors() {
+        let err = send_all(&ShortSocket, &[0; 32], (Ipv4Addr::LOCALHOST, 53)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::PartialSend(31, 32))
+        ));
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        assert!(send_all(&socket, &[0; 32], addr).is_ok());
+    }
+
+    #[test]
+    fn upstream_ttls_are_clamped() {
+        let port = mock_upstream(|request| {
+            let name = request.questions[0].name.clone();
+            let mut response = answer(
+                request,
+                vec![
+                    a_record(&name, [198, 51, 100, 1], 1),
+                    a_record(&name, [198, 51, 100, 2], 86400),
+                ],
+            );
+            to_bytes(&mut response)
+        });
+        let context = ServerContext {
+            forwarders: vec![(Ipv4Addr::LOCALHOST, port)],
+            resolver: test_config(),
+            min_ttl: Some(60),
+            max_ttl: Some(3600),
+            ..ServerContext::default()
+        };
+        let cache = Cache::new();
+        let request = DnsPacket::new().with_id(1).with_question(DnsQuestion::new(
+            "www.example.org".to_string(),
+            QueryType::A,
+        ));
+        let src = "127.0.0.1:5300".parse().unwrap();
+
+        let response = build_response(&context, &cache, &request, src);
+        let ttls: Vec<u32> = response.answers.iter().map(DnsRecord::ttl).collect();
+        assert_eq!(ttls, vec![60, 3600]);
+
+        let cached = cache.get("www.example.org", QueryType::A).unwrap();
+        let ttls: Vec<u32> = cached.answers.iter().map(DnsRecord::ttl).collect();
+        assert_eq!(ttls, vec![60, 3600]);
+    }
+
+    #[test]
+    fn nsec_records_round_trip() {
+        // Window 0 with A, NS, SOA, MX, TXT, AAAA, RRSIG and NSEC.
+        let record = DnsRecord::Nsec {
+            domain: "alpha.example.com".to_string(),
+            next_domain: "host.example.com".to_string(),
+            type_bitmap: vec![0, 6, 0x62, 0x01, 0x80, 0x08, 0x00, 0x03],
+            ttl: 3600,
+        };
+        assert_eq!(round_trip(&record), record);
+    }
+
+    #[test]
+    fn records_before_the_cut_off_are_kept() {
+        let question = DnsQuestion::new("example.com".to_string(), QueryType::A);
+        let mut response = answer(
+            &DnsPacket::new().with_question(question),
+            vec![
+                a_record("example.com", [192, 0, 2, 1], 60),
+                a_record("example.com", [192, 0, 2, 2], 60),
+            ],
+        );
+        let data = to_bytes(&mut response);
+
+        // Cut inside the second record's header.
+        let packet = parse(&data[..data.len() - 12]);
+        assert!(packet.header.truncated_message);
+        assert_eq!(packet.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        assert_eq!(normalize_name("Example.COM.").unwrap(), "example.com");
+        assert_eq!(normalize_name("example.com").unwrap(), "example.com");
+        assert_eq!(normalize_name(".").unwrap(), "");
+        assert!(normalize_name("example..com").is_err());
+        assert!(normalize_name(".example.com").is_err());
+    }
+
+    #[test]
+    fn apex_ns_and_soa_come_from_the_zone() {
+        let context = update_context();
+        context.zones.write().unwrap()[0].nameservers =
+            vec!["ns1.example.com".to_string(), "ns2.example.net".to_string()];
+
+        let response = ask(&context, "example.com", QueryType::Ns);
+        assert!(response.header.authoritative_answer);
+        let hosts: Vec<&str> = response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::Ns { host, .. } => Some(host.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(hosts, vec!["ns1.example.com", "ns2.example.net"]);
+
+        let response = ask(&context, "Example.com.", QueryType::Soa);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers, vec![soa_record("example.com", 1)]);
+    }
+
+    #[test]
+    fn blocked_names_are_not_resolved() {
+        let mut context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 example.org"),
+            blocklist: Blocklist::parse("# ads\nads.example.org\n*.tracker.test\n"),
+            ..ServerContext::default()
+        };
+
+        let response = ask(&context, "ads.example.org", QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::NxDomain);
+        let response = ask(&context, "pixel.eu.tracker.test", QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::NxDomain);
+        let response = ask(&context, "example.org", QueryType::A);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 10])]);
+
+        context.block_action = BlockAction::Sinkhole;
+        let response = ask(&context, "ads.example.org", QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(
+            response.addresses(),
+            vec![IpAddr::from(Ipv4Addr::UNSPECIFIED)]
+        );
+    }
+
+    #[test]
+    fn all_addresses_are_collected() {
+        let mut packet = answer_packet("example.com", [192, 0, 2, 1]);
+        packet
+            .answers
+            .push(a_record("example.com", [192, 0, 2, 2], 300));
+        packet.answers.push(DnsRecord::Aaaa {
+            domain: "example.com".to_string(),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: 300,
+        });
+
+        assert_eq!(
+            packet.addresses(),
+            vec![
+                IpAddr::from([192, 0, 2, 1]),
+                IpAddr::from([192, 0, 2, 2]),
+                "2001:db8::1".parse::<IpAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn written_responses_never_set_z() {
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            ..ServerContext::default()
+        };
+        let mut request = DnsPacket::new()
+            .with_id(1)
+            .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A));
+        request.header.z = true;
+        request.header.authentic_data = true;
+        request.header.checking_disabled = true;
+        let src = "127.0.0.1:5300".parse().unwrap();
+
+        let mut response = build_response(&context, &Cache::new(), &request, src);
+        response.header.z = true;
+        let data = to_bytes(&mut response);
+        assert_eq!(data[3] & (1 << 6), 0);
+
+        let response = parse(&data);
+        assert!(!response.header.z);
+        assert!(!response.header.authentic_data);
+        assert!(response.header.checking_disabled);
+    }
+
+    #[test]
+    fn tcp_queries_get_length_prefixed_answers() {
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            ..ServerContext::default()
+        };
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let query = to_bytes(
+            &mut DnsPacket::new()
+                .with_id(8)
+                .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A)),
+        );
+
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                let (mut stream, _) = listener.accept().unwrap();
+                handle_query_tcp(&mut stream, &context, &Cache::new())
+            });
+
+            write_tcp_message(&mut client, &query).unwrap();
+            let mut len_bytes = [0; 2];
+            client.read_exact(&mut len_bytes).unwrap();
+            let mut data = vec![0; u16::from_be_bytes(len_bytes) as usize];
+            client.read_exact(&mut data).unwrap();
+
+            let response = parse(&data);
+            assert_eq!(response.header.id, 8);
+            assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 10])]);
+
+            drop(client);
+            server.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn parse_errors_name_the_section_and_record() {
+        let mut data = vec![0, 1, 0x80, 0, 0, 0, 0, 0, 0, 1, 0, 0];
+        data.extend_from_slice(b"\x07example\x03com\x00");
+        // An A record claiming five bytes of address.
+        data.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 5, 192, 0, 2, 1, 0]);
+
+        let err =
+            DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "failed parsing authority record 1");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<DnsPacketError>(),
+            Some(DnsPacketError::RdataLengthMismatch(QueryType::A, 5, 4))
+        ));
+    }
+
+    #[test]
+    fn lookup_once_returns_referrals_unfollowed() {
+        let asked = Arc::new(Mutex::new(0));
+        let port = {
+            let asked = asked.clone();
+            mock_upstream(move |request| {
+                *asked.lock().unwrap() += 1;
+                let mut response = referral(request, "example.com", "ns.example.com");
+                response
+                    .resources
+                    .push(a_record("ns.example.com", [127, 0, 0, 1], 300));
+                to_bytes(&mut response)
+            })
+        };
+
+        let packet = lookup_once(
+            "www.example.com",
+            QueryType::A,
+            (Ipv4Addr::LOCALHOST, port),
+            false,
+            &test_config(),
+        )
+        .unwrap();
+        assert!(packet.answers.is_empty());
+        assert_eq!(
+            packet.get_unresolved_ns_all("www.example.com"),
+            vec!["ns.example.com"]
+        );
+        assert_eq!(
+            packet.get_resolved_ns_all("www.example.com"),
+            vec![Ipv4Addr::LOCALHOST]
+        );
+        assert_eq!(*asked.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn sibling_names_reuse_cached_glue() {
+        let root_queries = Arc::new(Mutex::new(0));
+        let port = {
+            let root_queries = root_queries.clone();
+            mock_upstream(move |request| {
+                *root_queries.lock().unwrap() += 1;
+                let mut response = referral(request, "example.com", "ns.example.com");
+                response
+                    .resources
+                    .push(a_record("ns.example.com", [127, 0, 0, 2], 300));
+                to_bytes(&mut response)
+            })
+        };
+        let nameserver = Ipv4Addr::new(127, 0, 0, 2);
+        mock_upstream_at((nameserver, port), |request| {
+            let name = request.questions[0].name.clone();
+            to_bytes(&mut answer(
+                request,
+                vec![a_record(&name, [192, 0, 2, 1], 300)],
+            ))
+        });
+
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+        let cache = Cache::new();
+        let rtts = RttTracker::new();
+        let resolve = |name: &str| {
+            let query = QueryContext::new(1, DnsQuestion::new(name.to_string(), QueryType::A));
+            recursive_lookup(name, QueryType::A, &config, &rtts, &cache, &query).unwrap();
+            query.servers()
+        };
+
+        assert_eq!(
+            resolve("www.example.com"),
+            vec![Ipv4Addr::LOCALHOST, nameserver]
+        );
+        assert_eq!(resolve("mail.example.com"), vec![nameserver]);
+        assert_eq!(*root_queries.lock().unwrap(), 1);
+
+        // The glue stays with the delegation rather than being an answer.
+        assert!(cache.get("ns.example.com", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn referrals_are_not_cached_as_ns_answers() {
+        let mut request = DnsPacket::new();
+        request.questions.push(DnsQuestion::new(
+            "www.example.com".to_string(),
+            QueryType::A,
+        ));
+        let response = referral(&request, "example.com", "ns.example.com");
+        let cache = Cache::new();
+
+        cache_referral(&cache, &response, "www.example.com");
+        assert!(cache.get("example.com", QueryType::Ns).is_none());
+
+        // The zone's own answer to an NS query isn't replaced by a referral
+        // to it either.
+        let mut own = DnsPacket::new();
+        own.answers.push(DnsRecord::Ns {
+            domain: "example.com".to_string(),
+            host: "a.iana-servers.net".to_string(),
+            ttl: 300,
+        });
+        cache.put("example.com", QueryType::Ns, own.clone(), 300);
+        cache_referral(&cache, &response, "www.example.com");
+        assert_eq!(
+            cache.get("example.com", QueryType::Ns).unwrap().answers,
+            own.answers
+        );
+        assert!(cache.get_delegation("example.com").is_some());
+    }
+
+    #[test]
+    fn question_returns_the_first_question() {
+        let question = DnsQuestion::new("example.com".to_string(), QueryType::A);
+        let packet = DnsPacket::new().with_question(question.clone());
+        assert_eq!(packet.question(), Some(&question));
+
+        assert_eq!(DnsPacket::new().question(), None);
+    }
+
+    #[test]
+    fn cname_only_answers_are_followed() {
+        let port = mock_upstream(|request| {
+            let name = request.questions[0].name.to_lowercase();
+            let mut response = match name.as_str() {
+                "www.example.com" => answer(
+                    request,
+                    vec![DnsRecord::Cname {
+                        domain: name.clone(),
+                        host: "web.example.net".to_string(),
+                        ttl: 300,
+                    }],
+                ),
+                _ => answer(request, vec![a_record(&name, [192, 0, 2, 80], 300)]),
+            };
+            to_bytes(&mut response)
+        });
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+
+        let response = recursive_lookup(
+            "www.example.com",
+            QueryType::A,
+            &config,
+            &RttTracker::new(),
+            &Cache::new(),
+            &QueryContext::new(1, question),
+        )
+        .unwrap();
+        assert!(matches!(
+            &response.answers[0],
+            DnsRecord::Cname { host, .. } if host == "web.example.net"
+        ));
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 80])]);
+    }
+
+    #[test]
+    fn synthesized_answers_carry_the_configured_ttl() {
+        let response = ask(&ServerContext::default(), "localhost", QueryType::A);
+        assert_eq!(response.answers[0].ttl(), DEFAULT_SYNTHESIZED_TTL);
+
+        let context = ServerContext {
+            synthesized_ttl: Some(42),
+            blocklist: Blocklist::parse("ads.example.org"),
+            block_action: BlockAction::Sinkhole,
+            ..ServerContext::default()
+        };
+        let response = ask(&context, "localhost", QueryType::A);
+        assert_eq!(response.answers[0].ttl(), 42);
+        let response = ask(&context, "ads.example.org", QueryType::A);
+        assert_eq!(response.answers[0].ttl(), 42);
+    }
+
+    #[test]
+    fn preloaded_records_are_served_from_the_cache() {
+        let cache = Cache::new();
+        cache.preload(vec![
+            a_record("www.example.org", [192, 0, 2, 1], 600),
+            a_record("www.example.org", [192, 0, 2, 2], 300),
+            DnsRecord::Aaaa {
+                domain: "www.example.org".to_string(),
+                addr: "2001:db8::1".parse().unwrap(),
+                ttl: 600,
+            },
+        ]);
+
+        let mut types = cache.cached_types("www.example.org");
+        types.sort_by_key(|qtype| u16::from(*qtype));
+        assert_eq!(types, vec![QueryType::A, QueryType::Aaaa]);
+
+        let request = DnsPacket::new().with_id(1).with_question(DnsQuestion::new(
+            "www.example.org".to_string(),
+            QueryType::A,
+        ));
+        let src = "127.0.0.1:5300".parse().unwrap();
+        let response = build_response(&ServerContext::default(), &cache, &request, src);
+        let mut addrs = response.addresses();
+        addrs.sort();
+        assert_eq!(
+            addrs,
+            vec![IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])]
+        );
+
+        // The group lives as long as its shortest TTL.
+        let ttl = cache
+            .entries()
+            .into_iter()
+            .find(|(_, qtype, _)| *qtype == QueryType::A)
+            .map(|(_, _, ttl)| ttl);
+        assert_eq!(ttl, Some(300));
+    }
+
+    #[test]
+    fn compressed_question_names_are_rejected_in_strict_mode() {
+        let mut data = vec![0, 9, 1, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        data.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        let context = ServerContext {
+            strict_question_names: true,
+            hosts: parse_hosts("192.0.2.10 example.com"),
+            ..ServerContext::default()
+        };
+        let src = "127.0.0.1:5300".parse().unwrap();
+
+        let mut req_buffer = BytePacketBuffer::from_bytes(&data).unwrap();
+        req_buffer.reject_question_pointers = true;
+        let response =
+            resolve_request(&context, &Cache::new(), &mut req_buffer, data.len(), src).unwrap();
+        assert_eq!(response.header.id, 9);
+        assert_eq!(response.header.rescode, ResultCode::FormErr);
+
+        let mut req_buffer = BytePacketBuffer::from_bytes(&data).unwrap();
+        let response =
+            resolve_request(&context, &Cache::new(), &mut req_buffer, data.len(), src).unwrap();
+        assert_ne!(response.header.rescode, ResultCode::FormErr);
+    }
+
+    /// Keeps the rdata as a single character-string on the wire.
+    struct CharacterStringCodec;
+
+    impl RecordCodec for CharacterStringCodec {
+        fn read(&self, buffer: &mut BytePacketBuffer, _data_len: u16) -> anyhow::Result<Vec<u8>> {
+            let len = buffer.read()? as usize;
+            buffer.read_bytes(len)
+        }
+
+        fn write(&self, data: &[u8], buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
+            buffer.write_u8(data.len() as u8)?;
+            buffer.write_bytes(data)
+        }
+    }
+
+    #[test]
+    fn registered_codecs_round_trip_their_records() {
+        register_record_codec(65281, CharacterStringCodec).unwrap();
+        assert!(register_record_codec(1, CharacterStringCodec).is_err());
+
+        let record = DnsRecord::Unknown {
+            domain: "custom.example".to_string(),
+            qtype: 65281,
+            data: b"hello".to_vec(),
+            ttl: 60,
+        };
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer, QueryClass::In).unwrap();
+        let rdata = &buffer.buffer[buffer.position - 8..buffer.position];
+        assert_eq!(rdata, b"\x00\x06\x05hello");
+
+        assert_eq!(round_trip(&record), record);
+    }
+
+    #[test]
+    fn round_trip_times_are_recorded_per_nameserver() {
+        let port = mock_upstream(|request| {
+            thread::sleep(Duration::from_millis(50));
+            let name = request.questions[0].name.clone();
+            to_bytes(&mut answer(
+                request,
+                vec![a_record(&name, [192, 0, 2, 1], 300)],
+            ))
+        });
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+        let rtts = RttTracker::new();
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+
+        recursive_lookup(
+            "www.example.com",
+            QueryType::A,
+            &config,
+            &rtts,
+            &Cache::new(),
+            &QueryContext::new(1, question),
+        )
+        .unwrap();
+
+        let rtt = rtts.get(Ipv4Addr::LOCALHOST).unwrap();
+        assert!(rtt >= Duration::from_millis(50), "{:?}", rtt);
+        assert_eq!(rtts.snapshot().len(), 1);
+    }
+
+    fn ask_chaos(context: &ServerContext, name: &str) -> DnsPacket {
+        let mut question = DnsQuestion::new(name.to_string(), QueryType::Txt);
+        question.qclass = QueryClass::Ch;
+        let request = DnsPacket::new().with_id(1).with_question(question);
+        let src = "127.0.0.1:5300".parse().unwrap();
+        build_response(context, &Cache::new(), &request, src)
+    }
+
+    #[test]
+    fn chaos_probes_get_the_configured_strings_or_are_refused() {
+        let context = ServerContext {
+            chaos_version: Some("toy 1".to_string()),
+            ..ServerContext::default()
+        };
+
+        let response = ask_chaos(&context, "version.bind");
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert!(matches!(
+            &response.answers[..],
+            [DnsRecord::Txt { data, .. }] if data == &vec!["toy 1".to_string()]
+        ));
+
+        let response = ask_chaos(&context, "hostname.bind");
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+        assert!(response.answers.is_empty());
+        let response = ask_chaos(&ServerContext::default(), "version.bind");
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+    }
+
+    /// Runs `query` through the server the way `handle_query` does and
+    /// returns the bytes it would send back.
+    fn serve_udp(context: &ServerContext, query: &[u8]) -> Vec<u8> {
+        let mut req_buffer = BytePacketBuffer::new();
+        req_buffer.buffer[..query.len()].copy_from_slice(query);
+        let src = "127.0.0.1:5300".parse().unwrap();
+        let mut res_buffer =
+            respond(context, &Cache::new(), &mut req_buffer, query.len(), src).unwrap();
+        res_buffer
+            .get_range(0, res_buffer.position)
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn response_hooks_rewrite_what_is_sent() {
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            on_response: Some(ResponseHook::new(|packet| {
+                for record in &mut packet.answers {
+                    if let DnsRecord::A { addr, .. } = record {
+                        *addr = Ipv4Addr::new(10, 0, 0, 10);
+                    }
+                }
+            })),
+            ..ServerContext::default()
+        };
+        let query = to_bytes(
+            &mut DnsPacket::new()
+                .with_id(3)
+                .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A)),
+        );
+
+        let response = parse(&serve_udp(&context, &query));
+        assert_eq!(response.addresses(), vec![IpAddr::from([10, 0, 0, 10])]);
+    }
+
+    #[test]
+    fn only_forwarded_queries_ask_for_recursion() {
+        let desired = Arc::new(Mutex::new(Vec::new()));
+        let port = {
+            let desired = desired.clone();
+            mock_upstream(move |request| {
+                desired
+                    .lock()
+                    .unwrap()
+                    .push(request.header.recursion_desired);
+                let name = request.questions[0].name.clone();
+                to_bytes(&mut answer(
+                    request,
+                    vec![a_record(&name, [192, 0, 2, 1], 300)],
+                ))
+            })
+        };
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+
+        recursive_lookup(
+            "www.example.com",
+            QueryType::A,
+            &config,
+            &RttTracker::new(),
+            &Cache::new(),
+            &QueryContext::new(1, question),
+        )
+        .unwrap();
+        forward_lookup(
+            "www.example.com",
+            QueryType::A,
+            &[(Ipv4Addr::LOCALHOST, port)],
+            &config,
+        )
+        .unwrap();
+
+        assert_eq!(*desired.lock().unwrap(), vec![false, true]);
+    }
+
+    #[test]
+    fn query_packets_build_fluently() {
+        let question = DnsQuestion::new("example.com".to_string(), QueryType::Aaaa);
+        let packet = DnsPacket::new()
+            .with_id(77)
+            .with_question(question.clone())
+            .with_recursion_desired(true);
+
+        assert_eq!(packet.header.id, 77);
+        assert!(packet.header.recursion_desired);
+        assert!(!packet.header.response);
+        assert_eq!(packet.questions, vec![question]);
+
+        let packet = parse(&to_bytes(&mut packet.with_recursion_desired(false)));
+        assert!(!packet.header.recursion_desired);
+        assert_eq!(packet.questions.len(), 1);
+    }
+
+    #[test]
+    fn any_queries_are_answered_with_every_cached_type() {
+        let cache = Cache::new();
+        cache.preload(vec![
+            a_record("example.org", [192, 0, 2, 1], 600),
+            DnsRecord::Mx {
+                domain: "example.org".to_string(),
+                priority: 10,
+                host: "mail.example.org".to_string(),
+                ttl: 600,
+            },
+            DnsRecord::Txt {
+                domain: "example.org".to_string(),
+                data: vec!["v=spf1 -all".to_string()],
+                ttl: 600,
+            },
+        ]);
+        let request = DnsPacket::new()
+            .with_id(1)
+            .with_question(DnsQuestion::new("example.org".to_string(), QueryType::Any));
+        let src = "127.0.0.1:5300".parse().unwrap();
+
+        let response = build_response(&ServerContext::default(), &cache, &request, src);
+        let mut types: Vec<u16> = response
+            .answers
+            .iter()
+            .map(|record| record.query_type().into())
+            .collect();
+        types.sort();
+        assert_eq!(types, vec![1, 15, 16]);
+
+        let context = ServerContext {
+            minimal_any: true,
+            ..ServerContext::default()
+        };
+        let response = build_response(&context, &cache, &request, src);
+        assert!(matches!(
+            &response.answers[..],
+            [DnsRecord::Hinfo { cpu, .. }] if cpu == "RFC8482"
+        ));
+    }
+
+    #[test]
+    fn hinfo_fields_over_255_bytes_are_rejected() {
+        let record = DnsRecord::Hinfo {
+            domain: "example.org".to_string(),
+            cpu: "x".repeat(300),
+            os: String::new(),
+            ttl: 600,
+        };
+        let mut buffer = BytePacketBuffer::new();
+
+        let err = record.write(&mut buffer, QueryClass::In).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BytePacketBufferError>(),
+            Some(BytePacketBufferError::CharacterStringTooLong(300))
+        ));
+    }
+
+    fn dual_stack_packet() -> DnsPacket {
+        let mut packet = answer_packet("example.com", [192, 0, 2, 1]);
+        packet.answers.push(DnsRecord::Aaaa {
+            domain: "example.com".to_string(),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: 300,
+        });
+        packet
+    }
+
+    #[test]
+    fn the_preferred_address_family_is_chosen() {
+        let v4 = IpAddr::from([192, 0, 2, 1]);
+        let v6 = "2001:db8::1".parse::<IpAddr>().unwrap();
+        let mut packet = dual_stack_packet();
+
+        assert_eq!(packet.preferred_address(false), Some(v4));
+        assert_eq!(packet.preferred_address(true), Some(v6));
+        assert_eq!(
+            answer_packet("example.com", [192, 0, 2, 1]).preferred_address(true),
+            Some(v4)
+        );
+        assert_eq!(DnsPacket::new().preferred_address(true), None);
+
+        packet.prefer_family(true);
+        assert_eq!(packet.addresses(), vec![v6, v4]);
+        packet.prefer_family(false);
+        assert_eq!(packet.addresses(), vec![v4, v6]);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn responses_are_held_back_by_the_configured_delay() {
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            response_delay: Some(Duration::from_millis(100)),
+            ..ServerContext::default()
+        };
+        let query = to_bytes(
+            &mut DnsPacket::new()
+                .with_id(3)
+                .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A)),
+        );
+
+        let started = Instant::now();
+        let response = parse(&serve_udp(&context, &query));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 10])]);
+    }
+
+    #[test]
+    fn cds_and_cdnskey_records_round_trip() {
+        let cds = DnsRecord::Cds {
+            domain: "example.com".to_string(),
+            key_tag: 2371,
+            algorithm: 13,
+            digest_type: 2,
+            digest: (0..32).collect(),
+            ttl: 3600,
+        };
+        assert_eq!(round_trip(&cds), cds);
+
+        let cdnskey = DnsRecord::Cdnskey {
+            domain: "example.com".to_string(),
+            flags: 257,
+            protocol: 3,
+            algorithm: 13,
+            public_key: (0..64).rev().collect(),
+            ttl: 3600,
+        };
+        assert_eq!(round_trip(&cdnskey), cdnskey);
+    }
+
+    #[test]
+    fn large_rrsets_are_truncated_over_udp_and_complete_over_tcp() {
+        let hosts: String = (1..=60)
+            .map(|i| format!("192.0.2.{} big.lan\n", i))
+            .collect();
+        let context = ServerContext {
+            hosts: parse_hosts(&hosts),
+            ..ServerContext::default()
+        };
+        let query = to_bytes(
+            &mut DnsPacket::new()
+                .with_id(3)
+                .with_question(DnsQuestion::new("big.lan".to_string(), QueryType::A)),
+        );
+
+        let data = serve_udp(&context, &query);
+        assert!(data.len() <= MAX_BUFFER_SIZE);
+        let response = parse(&data);
+        assert!(response.header.truncated_message);
+        assert!(response.answers.len() < 60);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut res_buffer = thread::scope(|scope| {
+            scope.spawn(|| {
+                let (mut stream, _) = listener.accept().unwrap();
+                handle_query_tcp(&mut stream, &context, &Cache::new())
+            });
+            write_tcp_message(&mut client, &query).unwrap();
+            let response = read_tcp_message(&mut client).unwrap();
+            client.shutdown(std::net::Shutdown::Both).unwrap();
+            response
+        });
+        let response = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers.len(), 60);
+    }
+
+    #[test]
+    fn trailing_bytes_after_the_sections_are_ignored() {
+        let question = DnsQuestion::new("example.com".to_string(), QueryType::A);
+        let mut response = answer(
+            &DnsPacket::new().with_id(5).with_question(question),
+            vec![a_record("example.com", [192, 0, 2, 1], 60)],
+        );
+        let mut data = to_bytes(&mut response);
+        data.extend_from_slice(&[0xAB; 20]);
+
+        let packet = parse(&data);
+        assert!(!packet.header.truncated_message);
+        assert_eq!(packet.header.id, 5);
+        assert_eq!(packet.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+        assert!(packet.authorities.is_empty());
+        assert!(packet.resources.is_empty());
+    }
+
+    #[test]
+    fn reserved_label_types_are_rejected() {
+        for len in [0x40, 0x7F, 0x80] {
+            let mut buffer = BytePacketBuffer::from_bytes(&[len, b'a', 0]).unwrap();
+            let mut name = String::new();
+            let err = buffer.read_qname(&mut name).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<BytePacketBufferError>(),
+                Some(BytePacketBufferError::ReservedLabelType(byte)) if *byte == len
+            ));
+        }
+    }
+
+    fn cname(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::Cname {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn lookups_return_the_whole_cname_chain_in_order() {
+        let port = mock_upstream(|request| {
+            let name = request.questions[0].name.to_lowercase();
+            let answers = match name.as_str() {
+                "www.example.com" => vec![cname(&name, "cdn.example.net")],
+                "cdn.example.net" => vec![cname(&name, "edge.example.org")],
+                "loop-a.example.com" => vec![cname(&name, "loop-b.example.com")],
+                "loop-b.example.com" => vec![cname(&name, "loop-a.example.com")],
+                _ => vec![a_record(&name, [192, 0, 2, 1], 300)],
+            };
+            to_bytes(&mut answer(request, answers))
+        });
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+        let query =
+            |name: &str| QueryContext::new(1, DnsQuestion::new(name.to_string(), QueryType::A));
+
+        let response = lookup(&query("www.example.com"), &config).unwrap();
+        assert_eq!(
+            response.answers,
+            vec![
+                cname("www.example.com", "cdn.example.net"),
+                cname("cdn.example.net", "edge.example.org"),
+                a_record("edge.example.org", [192, 0, 2, 1], 300),
+            ]
+        );
+
+        let err = lookup(&query("loop-a.example.com"), &config).unwrap_err();
+        assert!(err.to_string().contains("CNAME loop"));
+    }
+
+    #[test]
+    fn unknown_record_types_keep_their_rdata() {
+        // OPENPGPKEY, which has no variant of its own.
+        let record = DnsRecord::Unknown {
+            domain: "user._openpgpkey.example.com".to_string(),
+            qtype: 61,
+            data: vec![0x99, 0x01, 0x0d, 0x04, 0x5a, 0x00, 0xff],
+            ttl: 3600,
+        };
+        assert_eq!(round_trip(&record), record);
+
+        let mut response = DnsPacket::new();
+        response.answers.push(record.clone());
+        assert_eq!(parse(&to_bytes(&mut response)).answers, vec![record]);
+    }
+
+    #[test]
+    fn unknown_records_survive_parse_and_serialize() {
+        let mut data = vec![0, 1, 0x81, 0x80, 0, 0, 0, 1, 0, 0, 0, 0];
+        data.extend_from_slice(b"\x07example\x03com\x00");
+        data.extend_from_slice(&[0, 99, 0, 1, 0, 0, 0, 60, 0, 3, 0xde, 0xad, 0xbf]);
+
+        let mut packet = parse(&data);
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::Unknown {
+                domain: "example.com".to_string(),
+                qtype: 99,
+                data: vec![0xde, 0xad, 0xbf],
+                ttl: 60,
+            }]
+        );
+        assert_eq!(to_bytes(&mut packet), data);
+    }
+
+    #[test]
+    fn negative_ttls_come_from_the_soa_minimum() {
+        let question = DnsQuestion::new("missing.example.com".to_string(), QueryType::A);
+        let soa = soa_record("example.com", 1);
+        let packet = DnsPacket::nxdomain_for(question.clone(), soa.clone());
+        // The SOA's minimum is 300 and its own TTL 3600.
+        assert_eq!(packet.negative_ttl(), Some(300));
+
+        let mut soa = soa;
+        soa.set_ttl(60);
+        let packet = DnsPacket::nxdomain_for(question, soa);
+        assert_eq!(packet.negative_ttl(), Some(60));
+
+        assert_eq!(DnsPacket::new().negative_ttl(), None);
+    }
+
+    #[test]
+    fn the_first_nameserver_resolved_is_used() {
+        let port = mock_upstream(|request| {
+            let name = request.questions[0].name.to_lowercase();
+            let mut response = match name.as_str() {
+                "www.example.com" => {
+                    let mut response = referral(request, "example.com", "slow.ns.test");
+                    response.authorities.push(DnsRecord::Ns {
+                        domain: "example.com".to_string(),
+                        host: "fast.ns.test".to_string(),
+                        ttl: 300,
+                    });
+                    response
+                }
+                "fast.ns.test" => answer(request, vec![a_record(&name, [127, 0, 0, 2], 300)]),
+                _ => {
+                    let mut response = referral(request, "slow.ns.test", "ns.slow.ns.test");
+                    response
+                        .resources
+                        .push(a_record("ns.slow.ns.test", [127, 0, 0, 3], 300));
+                    response
+                }
+            };
+            to_bytes(&mut response)
+        });
+        mock_upstream_at((Ipv4Addr::new(127, 0, 0, 2), port), |request| {
+            let name = request.questions[0].name.clone();
+            to_bytes(&mut answer(
+                request,
+                vec![a_record(&name, [192, 0, 2, 2], 300)],
+            ))
+        });
+        mock_upstream_at((Ipv4Addr::new(127, 0, 0, 3), port), |request| {
+            thread::sleep(Duration::from_millis(200));
+            let name = request.questions[0].name.clone();
+            to_bytes(&mut answer(
+                request,
+                vec![a_record(&name, [127, 0, 0, 3], 300)],
+            ))
+        });
+
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ns_concurrency: 2,
+            ..test_config()
+        };
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let response = lookup(&QueryContext::new(1, question), &config).unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 2)));
+    }
+
+    #[test]
+    fn headers_tell_queries_from_responses() {
+        let query = DnsPacket::new().with_id(1);
+        assert!(query.header.is_query());
+        assert!(!query.header.is_response());
+
+        let response = query.into_response();
+        assert!(response.header.is_response());
+        assert!(!response.header.is_query());
+    }
+
+    #[test]
+    fn zones_are_transferred_from_a_primary_and_served() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let primary = listener.local_addr().unwrap();
+        let ns = DnsRecord::Ns {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: 3600,
+        };
+        let messages = vec![
+            vec![
+                soa_record("example.com", 7),
+                ns,
+                a_record("www.example.com", [192, 0, 2, 1], 60),
+            ],
+            vec![
+                a_record("mail.example.com", [192, 0, 2, 25], 60),
+                soa_record("example.com", 7),
+            ],
+        ];
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut req_buffer = read_tcp_message(&mut stream).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+            assert_eq!(request.questions[0].qtype, QueryType::Axfr);
+            for answers in messages {
+                let mut response = answer(&request, answers);
+                write_tcp_message(&mut stream, &to_bytes(&mut response)).unwrap();
+            }
+        });
+
+        let zone = Zone::transfer("example.com", primary, &test_config()).unwrap();
+        server.join().unwrap();
+        assert_eq!(zone.soa, soa_record("example.com", 7));
+        assert_eq!(zone.nameservers, vec!["ns1.example.com".to_string()]);
+        assert_eq!(zone.records.len(), 2);
+
+        let context = ServerContext::default();
+        context.zones.write().unwrap().push(zone);
+        let response = ask(&context, "mail.example.com", QueryType::A);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 25])]);
+    }
+
+    #[test]
+    fn transfers_from_a_silent_primary_time_out() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let primary = listener.local_addr().unwrap();
+        let config = ResolverConfig {
+            timeout: Duration::from_millis(100),
+            ..ResolverConfig::default()
+        };
+
+        let started = Instant::now();
+        assert!(Zone::transfer("example.com", primary, &config).is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        drop(listener);
+    }
+
+    #[test]
+    fn simultaneous_identical_queries_share_one_resolution() {
+        let asked = Arc::new(Mutex::new(0));
+        let port = {
+            let asked = asked.clone();
+            mock_upstream(move |request| {
+                *asked.lock().unwrap() += 1;
+                thread::sleep(Duration::from_millis(200));
+                let name = request.questions[0].name.clone();
+                to_bytes(&mut answer(
+                    request,
+                    vec![a_record(&name, [192, 0, 2, 1], 300)],
+                ))
+            })
+        };
+        let context = ServerContext {
+            forwarders: vec![(Ipv4Addr::LOCALHOST, port)],
+            resolver: ResolverConfig {
+                timeout: Duration::from_secs(2),
+                ..test_config()
+            },
+            ..ServerContext::default()
+        };
+
+        let responses: Vec<DnsPacket> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| ask(&context, "www.example.org", QueryType::A)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        assert_eq!(*asked.lock().unwrap(), 1);
+        for response in responses {
+            assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+        }
+    }
+
+    #[test]
+    fn outbound_queries_are_sent_from_the_configured_source() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut data = [0; MAX_BUFFER_SIZE];
+            let (len, src) = socket.recv_from(&mut data).unwrap();
+            let request = parse_request(&data[..len]);
+            socket
+                .send_to(&to_bytes(&mut request.into_response()), src)
+                .unwrap();
+            src
+        });
+
+        let config = ResolverConfig {
+            source: Ipv4Addr::new(127, 0, 0, 5),
+            ..test_config()
+        };
+        lookup_once(
+            "example.com",
+            QueryType::A,
+            (Ipv4Addr::LOCALHOST, port),
+            false,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(server.join().unwrap().ip(), IpAddr::from([127, 0, 0, 5]));
+    }
+
+    #[test]
+    fn header_flags_print_like_dig() {
+        let mut header = DnsHeader::new();
+        header.response = true;
+        header.recursion_desired = true;
+        header.recursion_available = true;
+        assert_eq!(header.flags_string(), "qr rd ra");
+
+        header.authoritative_answer = true;
+        header.truncated_message = true;
+        assert_eq!(header.flags_string(), "qr aa tc rd ra");
+        assert_eq!(DnsHeader::new().flags_string(), "");
+    }
+
+    #[test]
+    fn authoritative_only_servers_refuse_names_outside_their_zones() {
+        let mut context = update_context();
+        context.authoritative_only = true;
+
+        let response = ask(&context, "www.example.org", QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+        assert!(response.answers.is_empty());
+
+        let response = ask(&context, "www.example.com", QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+    }
+
+    #[test]
+    fn cache_entries_report_live_remaining_ttls() {
+        let cache = Cache::new();
+        cache.put(
+            "a.test",
+            QueryType::A,
+            answer_packet("a.test", [192, 0, 2, 1]),
+            300,
+        );
+        cache.put("b.test", QueryType::Mx, DnsPacket::new(), 60);
+
+        let mut entries = cache.entries();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            entries,
+            vec![
+                ("a.test".to_string(), QueryType::A, 300),
+                ("b.test".to_string(), QueryType::Mx, 60),
+            ]
+        );
+
+        thread::sleep(Duration::from_millis(1100));
+        let remaining = cache
+            .entries()
+            .into_iter()
+            .find(|(name, _, _)| name == "a.test")
+            .map(|(_, _, ttl)| ttl)
+            .unwrap();
+        assert!(remaining < 300);
+    }
+
+    #[test]
+    fn record_targets_must_be_valid_hostnames() {
+        assert!(is_valid_hostname("mail.example.com"));
+        assert!(is_valid_hostname("mail-1.example.com."));
+        assert!(is_valid_hostname("localhost"));
+        assert!(!is_valid_hostname(""));
+        assert!(!is_valid_hostname("-mail.example.com"));
+        assert!(!is_valid_hostname("mail_1.example.com"));
+        assert!(!is_valid_hostname("mail..example.com"));
+        assert!(!is_valid_hostname(&format!("{}.com", "a".repeat(64))));
+        assert!(!is_valid_hostname(&["a"; 128].join(".")));
+
+        assert!(DnsRecord::mx("example.com", 10, "mail.example.com", 60).is_ok());
+        assert!(DnsRecord::ns("example.com", "ns1.example.com", 60).is_ok());
+        assert!(matches!(
+            DnsRecord::cname("www.example.com", "bad name", 60),
+            Err(NameError::InvalidHostname(_))
+        ));
+        assert!(DnsRecord::mx("example.com", 10, "", 60).is_err());
+    }
+
+    #[test]
+    fn the_next_glue_address_is_tried_when_one_times_out() {
+        let port = mock_upstream(|request| {
+            let mut response = referral(request, "example.com", "ns.example.com");
+            for addr in [[127, 0, 0, 2], [127, 0, 0, 3]] {
+                response
+                    .resources
+                    .push(a_record("ns.example.com", addr, 300));
+            }
+            to_bytes(&mut response)
+        });
+        let _silent = UdpSocket::bind((Ipv4Addr::new(127, 0, 0, 2), port)).unwrap();
+        mock_upstream_at((Ipv4Addr::new(127, 0, 0, 3), port), |request| {
+            let name = request.questions[0].name.clone();
+            to_bytes(&mut answer(
+                request,
+                vec![a_record(&name, [192, 0, 2, 1], 300)],
+            ))
+        });
+
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            timeout: Duration::from_millis(200),
+            ..test_config()
+        };
+        let query = QueryContext::new(
+            1,
+            DnsQuestion::new("www.example.com".to_string(), QueryType::A),
+        );
+        let response = lookup(&query, &config).unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(
+            query.servers(),
+            vec![
+                Ipv4Addr::LOCALHOST,
+                Ipv4Addr::new(127, 0, 0, 2),
+                Ipv4Addr::new(127, 0, 0, 3)
+            ]
+        );
+    }
+
+    fn query_with_opcode(opcode: u8) -> Vec<u8> {
+        let mut request = DnsPacket::new()
+            .with_id(12)
+            .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A));
+        request.header.opcode = opcode;
+        to_bytes(&mut request)
+    }
+
+    #[test]
+    fn opcodes_outside_the_allowlist_get_notimp() {
+        let mut context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            ..ServerContext::default()
+        };
+
+        let response = parse(&serve_udp(&context, &query_with_opcode(OPCODE_NOTIFY)));
+        assert_eq!(response.header.id, 12);
+        assert_eq!(response.header.opcode, OPCODE_NOTIFY);
+        assert_eq!(response.header.rescode, ResultCode::NotImp);
+        let response = parse(&serve_udp(&context, &query_with_opcode(OPCODE_QUERY)));
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+
+        context.allowed_opcodes = Some(vec![OPCODE_NOTIFY]);
+        let response = parse(&serve_udp(&context, &query_with_opcode(OPCODE_QUERY)));
+        assert_eq!(response.header.rescode, ResultCode::NotImp);
+    }
+
+    #[test]
+    fn records_added_by_an_update_can_be_queried() {
+        let context = update_context();
+        let added = a_record("mail.example.com", [192, 0, 2, 25], 60);
+
+        let response = send_update(&context, &update_message(vec![added]), "127.0.0.1:5300");
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.header.opcode, OPCODE_UPDATE);
+
+        let response = ask(&context, "mail.example.com", QueryType::A);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 25])]);
+    }
+
+    #[test]
+    fn sections_too_large_to_count_are_errors() {
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![a_record("a.test", [192, 0, 2, 1], 60); u16::MAX as usize + 1];
+
+        let mut buffer = BytePacketBuffer::with_size(MAX_TCP_MESSAGE_SIZE);
+        let err = packet.write(&mut buffer).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DnsPacketError>(),
+            Some(DnsPacketError::SectionTooLarge("answer", 65536))
+        ));
+    }
+
+    /// Notes the log prefix whenever the cache is consulted, which happens in
+    /// the middle of resolving a query.
+    #[derive(Default)]
+    struct PrefixRecordingCache {
+        prefixes: Mutex<Vec<String>>,
+    }
+
+    impl CacheStore for PrefixRecordingCache {
+        fn get(&self, _qname: &str, _qtype: QueryType) -> Option<DnsPacket> {
+            self.prefixes.lock().unwrap().push(log_prefix());
+            None
+        }
+
+        fn put(&self, _qname: &str, _qtype: QueryType, _packet: DnsPacket, _ttl: u32) {}
+
+        fn remove(&self, _qname: &str, _qtype: QueryType) {}
+    }
+
+    #[test]
+    fn log_lines_carry_the_query_id_and_source() {
+        let port = mock_upstream(|request| {
+            let name = request.questions[0].name.clone();
+            to_bytes(&mut answer(
+                request,
+                vec![a_record(&name, [192, 0, 2, 1], 300)],
+            ))
+        });
+        let context = ServerContext {
+            forwarders: vec![(Ipv4Addr::LOCALHOST, port)],
+            resolver: test_config(),
+            ..ServerContext::default()
+        };
+        let cache = PrefixRecordingCache::default();
+        let query = to_bytes(
+            &mut DnsPacket::new()
+                .with_id(4321)
+                .with_question(DnsQuestion::new(
+                    "www.example.org".to_string(),
+                    QueryType::A,
+                )),
+        );
+
+        let mut req_buffer = BytePacketBuffer::from_bytes(&query).unwrap();
+        let src = "127.0.0.1:5300".parse().unwrap();
+        resolve_request(&context, &cache, &mut req_buffer, query.len(), src).unwrap();
+
+        let prefixes = cache.prefixes.lock().unwrap();
+        assert!(!prefixes.is_empty());
+        for prefix in prefixes.iter() {
+            assert!(
+                prefix.ends_with(" [id 4321 from 127.0.0.1:5300]"),
+                "{}",
+                prefix
+            );
+        }
+        assert!(!log_prefix().contains('['));
+    }
+
+    #[test]
+    fn tsig_records_round_trip() {
+        let record = DnsRecord::Tsig {
+            domain: "transfer-key".to_string(),
+            algorithm: "hmac-sha256".to_string(),
+            time_signed: 0x0000_0102_0304_0506,
+            fudge: 300,
+            mac: vec![0xAA; 32],
+            original_id: 6666,
+            error: 0,
+            other: Vec::new(),
+        };
+        assert_eq!(round_trip(&record), record);
+
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer, QueryClass::In).unwrap();
+        let data = &buffer.buffer[..buffer.position];
+        // Owner, then type 250, class ANY and a TTL of 0.
+        assert_eq!(&data[14..24], &[0, 250, 0, 255, 0, 0, 0, 0, 0, 61]);
+        // The algorithm name, then the 48-bit signing time.
+        assert_eq!(&data[24..37], b"\x0bhmac-sha256\x00");
+        assert_eq!(&data[37..43], &[1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn answers_past_the_limit_are_dropped_with_tc() {
+        let hosts: String = (1..=10)
+            .map(|i| format!("192.0.2.{} big.lan\n", i))
+            .collect();
+        let mut context = ServerContext {
+            hosts: parse_hosts(&hosts),
+            max_answers: Some(4),
+            ..ServerContext::default()
+        };
+
+        let response = ask(&context, "big.lan", QueryType::A);
+        assert_eq!(response.answers.len(), 4);
+        assert!(response.header.truncated_message);
+
+        context.max_answers = Some(10);
+        let response = ask(&context, "big.lan", QueryType::A);
+        assert_eq!(response.answers.len(), 10);
+        assert!(!response.header.truncated_message);
+    }
+
+    #[test]
+    fn a_cleared_packet_writes_the_same_bytes_as_a_fresh_one() {
+        let fill = |packet: &mut DnsPacket, name: &str, addr: [u8; 4]| {
+            packet.header.id = 4321;
+            packet.header.response = true;
+            packet
+                .questions
+                .push(DnsQuestion::new(name.to_string(), QueryType::A));
+            packet.answers.push(a_record(name, addr, 300));
+        };
+
+        let mut reused = DnsPacket::new();
+        fill(&mut reused, "first.example.com", [192, 0, 2, 1]);
+        reused
+            .authorities
+            .push(a_record("ns.example.com", [192, 0, 2, 53], 300));
+        let mut buffer = BytePacketBuffer::new();
+        reused.write(&mut buffer).unwrap();
+
+        reused.clear();
+        buffer.reset();
+        assert_eq!(buffer.position(), 0);
+        assert!(buffer.buffer.iter().all(|&byte| byte == 0));
+        fill(&mut reused, "second.example.com", [192, 0, 2, 2]);
+        reused.write(&mut buffer).unwrap();
+
+        let mut fresh = DnsPacket::new();
+        fill(&mut fresh, "second.example.com", [192, 0, 2, 2]);
+        let mut fresh_buffer = BytePacketBuffer::new();
+        fresh.write(&mut fresh_buffer).unwrap();
+
+        assert_eq!(buffer.position(), fresh_buffer.position());
+        assert_eq!(buffer.buffer, fresh_buffer.buffer);
+    }
+
+    #[test]
+    fn records_longer_than_they_parse_are_rejected() {
+        let mut data = b"\x07example\x03com\x00".to_vec();
+        // An MX record declaring two bytes more than its preference and
+        // exchange take up.
+        data.extend_from_slice(&[0, 15, 0, 1, 0, 0, 0, 60, 0, 8, 0, 10]);
+        data.extend_from_slice(b"\x02mx\x00");
+        data.extend_from_slice(&[0, 0]);
+
+        let mut buffer = BytePacketBuffer::from_bytes(&data).unwrap();
+        let err = DnsRecord::read(&mut buffer).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DnsPacketError>(),
+            Some(DnsPacketError::RdataLengthMismatch(QueryType::Mx, 8, 6))
+        ));
+    }
+
+    #[test]
+    fn forwarding_fails_over_past_silent_and_servfail_forwarders() {
+        // Bound but never read, so queries to it time out.
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let silent_port = silent.local_addr().unwrap().port();
+        let failing_port = mock_upstream(|request| {
+            let mut response = request.into_response();
+            response.header.rescode = ResultCode::ServFail;
+            to_bytes(&mut response)
+        });
+        let answering_port = mock_upstream(|request| {
+            let name = request.questions[0].name.clone();
+            to_bytes(&mut answer(
+                request,
+                vec![a_record(&name, [198, 51, 100, 7], 300)],
+            ))
+        });
+        let config = ResolverConfig {
+            timeout: Duration::from_millis(100),
+            ..test_config()
+        };
+
+        let response = forward_lookup(
+            "www.example.org",
+            QueryType::A,
+            &[
+                (Ipv4Addr::LOCALHOST, silent_port),
+                (Ipv4Addr::LOCALHOST, failing_port),
+                (Ipv4Addr::LOCALHOST, answering_port),
+            ],
+            &config,
+        )
+        .unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.addresses(), vec![IpAddr::from([198, 51, 100, 7])]);
+
+        let response = forward_lookup(
+            "www.example.org",
+            QueryType::A,
+            &[
+                (Ipv4Addr::LOCALHOST, silent_port),
+                (Ipv4Addr::LOCALHOST, failing_port),
+            ],
+            &config,
+        )
+        .unwrap();
+        assert_eq!(response.header.rescode, ResultCode::ServFail);
+        drop(silent);
+    }
+
+    #[test]
+    fn queries_carry_a_client_cookie_and_keep_the_server_cookie() {
+        let server = Ipv4Addr::new(127, 0, 0, 6);
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let port = mock_upstream_at((server, 0), move |request| {
+            let cookie = match request.opt() {
+                Some(DnsRecord::Opt { options, .. }) => options
+                    .iter()
+                    .find(|option| option.code == EDNS_OPTION_COOKIE)
+                    .map(|option| option.data.clone()),
+                _ => None,
+            };
+            sender.lock().unwrap().send(cookie.clone()).unwrap();
+
+            let mut data = cookie.unwrap_or_default();
+            data.truncate(8);
+            data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            let mut response = request.into_response();
+            response.resources.push(DnsRecord::Opt {
+                packet_len: 1232,
+                flags: 0,
+                options: vec![EdnsOption {
+                    code: EDNS_OPTION_COOKIE,
+                    data,
+                }],
+            });
+            to_bytes(&mut response)
+        });
+        let client_cookie = cookie_jar().client_cookie(server).to_vec();
+
+        lookup_once(
+            "example.com",
+            QueryType::A,
+            (server, port),
+            false,
+            &test_config(),
+        )
+        .unwrap();
+        assert_eq!(receiver.recv().unwrap(), Some(client_cookie.clone()));
+        assert_eq!(
+            cookie_jar().server_cookie(server),
+            Some(vec![1, 2, 3, 4, 5, 6, 7, 8])
+        );
+
+        lookup_once(
+            "example.com",
+            QueryType::A,
+            (server, port),
+            false,
+            &test_config(),
+        )
+        .unwrap();
+        let mut echoed = client_cookie;
+        echoed.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(receiver.recv().unwrap(), Some(echoed));
+    }
+
+    #[test]
+    fn headers_round_trip_through_their_twelve_bytes() {
+        let mut header = DnsHeader::new();
+        header.id = 0xbeef;
+        header.response = true;
+        header.opcode = 5;
+        header.authoritative_answer = true;
+        header.recursion_desired = true;
+        header.recursion_available = true;
+        header.checking_disabled = true;
+        header.rescode = ResultCode::NxDomain;
+        header.questions = 1;
+        header.answers = 2;
+        header.authoritative_entries = 3;
+        header.resource_entries = 4;
+
+        let bytes = header.to_bytes();
+        assert_eq!(bytes, [0xbe, 0xef, 0xad, 0x93, 0, 1, 0, 2, 0, 3, 0, 4]);
+
+        let decoded = DnsHeader::from_bytes(&bytes);
+        assert_eq!(decoded.id, 0xbeef);
+        assert_eq!(decoded.opcode, 5);
+        assert_eq!(decoded.rescode, ResultCode::NxDomain);
+        assert_eq!(decoded.flags_string(), "qr aa rd ra cd");
+        assert!(!decoded.authentic_data && !decoded.z);
+        assert_eq!(decoded.resource_entries, 4);
+        assert_eq!(decoded.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn different_client_subnets_get_different_answers() {
+        let context = view_context();
+        let src = "192.0.2.53:5300".parse().unwrap();
+
+        let inside = build_response(
+            &context,
+            &Cache::new(),
+            &query_with_subnet("10.1.2.0/24"),
+            src,
+        );
+        let outside = build_response(
+            &context,
+            &Cache::new(),
+            &query_with_subnet("198.51.100.0/24"),
+            src,
+        );
+        assert_eq!(inside.addresses(), vec![IpAddr::from([10, 0, 0, 5])]);
+        assert_eq!(outside.addresses(), vec![IpAddr::from([203, 0, 113, 5])]);
+    }
+
+    #[test]
+    fn spoofing_protection_randomizes_queries_and_rejects_mismatches() {
+        let qname = "abcdefghijklmnopqrstuvwxyz.example.com";
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let intruder = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            loop {
+                let mut data = [0; MAX_BUFFER_SIZE];
+                let (len, src) = socket.recv_from(&mut data).unwrap();
+                let request = parse_request(&data[..len]);
+                let name = request.questions[0].name.clone();
+                sender
+                    .send((request.header.id, src.port(), name.clone()))
+                    .unwrap();
+
+                let mut genuine = answer(&request, vec![a_record(&name, [192, 0, 2, 1], 60)]);
+                let forged = answer(&request, vec![a_record(&name, [203, 0, 113, 1], 60)]);
+                let mut wrong_id = forged.clone();
+                wrong_id.header.id = request.header.id.wrapping_add(1);
+                let mut wrong_case = forged.clone();
+                wrong_case.questions[0].name = name
+                    .chars()
+                    .map(|c| {
+                        if c.is_ascii_uppercase() {
+                            c.to_ascii_lowercase()
+                        } else {
+                            c.to_ascii_uppercase()
+                        }
+                    })
+                    .collect();
+                let mut wrong_source = forged;
+
+                socket.send_to(&to_bytes(&mut wrong_id), src).unwrap();
+                socket.send_to(&to_bytes(&mut wrong_case), src).unwrap();
+                intruder.send_to(&to_bytes(&mut wrong_source), src).unwrap();
+                socket.send_to(&to_bytes(&mut genuine), src).unwrap();
+            }
+        });
+
+        let config = ResolverConfig {
+            spoofing_protection: true,
+            ..test_config()
+        };
+        let mut queries = Vec::new();
+        for _ in 0..3 {
+            let packet = lookup_once(
+                qname,
+                QueryType::A,
+                (Ipv4Addr::LOCALHOST, port),
+                false,
+                &config,
+            )
+            .unwrap();
+            assert_eq!(packet.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+            queries.push(receiver.recv().unwrap());
+        }
+
+        let (ids, ports): (Vec<_>, Vec<_>) =
+            queries.iter().map(|(id, port, _)| (*id, *port)).unzip();
+        assert!(ids.iter().any(|&id| id != ids[0]));
+        assert!(ports.iter().any(|&port| port != ports[0]));
+        for (_, _, name) in &queries {
+            assert!(name.eq_ignore_ascii_case(qname));
+            assert_ne!(name, qname);
+        }
+    }
+
+    #[test]
+    fn queries_with_a_cut_off_question_get_formerr() {
+        let mut query = vec![0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07exam");
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 example.com"),
+            ..ServerContext::default()
+        };
+
+        let response = parse(&serve_udp(&context, &query));
+        assert_eq!(response.header.id, 0x1234);
+        assert!(response.header.response);
+        assert_eq!(response.header.rescode, ResultCode::FormErr);
+        assert!(response.answers.is_empty());
+
+        // The same question, whole, is answered.
+        query.extend_from_slice(b"ple\x03com\x00\x00\x01\x00\x01");
+        let response = parse(&serve_udp(&context, &query));
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 10])]);
+    }
+
+    #[test]
+    fn packets_with_reordered_answers_are_semantically_equal() {
+        let question = DnsQuestion::new("example.com".to_string(), QueryType::A);
+        let mut first = DnsPacket::new().with_question(question.clone());
+        first.answers = vec![
+            a_record("example.com", [192, 0, 2, 1], 300),
+            a_record("example.com", [192, 0, 2, 2], 300),
+        ];
+        let mut second = DnsPacket::new().with_id(7).with_question(question);
+        second.answers = vec![
+            a_record("example.com", [192, 0, 2, 2], 60),
+            a_record("example.com", [192, 0, 2, 1], 60),
+        ];
+        assert!(first.semantically_eq(&second));
+        assert!(second.semantically_eq(&first));
+
+        let mut other_answer = second.clone();
+        other_answer.answers[0] = a_record("example.com", [192, 0, 2, 3], 60);
+        assert!(!first.semantically_eq(&other_answer));
+
+        let mut other_rescode = second.clone();
+        other_rescode.header.rescode = ResultCode::ServFail;
+        assert!(!first.semantically_eq(&other_rescode));
+
+        let mut fewer = second;
+        fewer.answers.pop();
+        assert!(!first.semantically_eq(&fewer));
+    }
+
+    #[test]
+    fn dname_answers_get_the_cname_they_imply() {
+        let port = mock_upstream(|request| {
+            to_bytes(&mut answer(
+                request,
+                vec![
+                    DnsRecord::Dname {
+                        domain: "old.example.com".to_string(),
+                        target: "new.example.com".to_string(),
+                        ttl: 300,
+                    },
+                    a_record("host.new.example.com", [192, 0, 2, 80], 300),
+                ],
+            ))
+        });
+        let context = ServerContext {
+            forwarders: vec![(Ipv4Addr::LOCALHOST, port)],
+            resolver: test_config(),
+            ..ServerContext::default()
+        };
+
+        let response = ask(&context, "host.old.example.com", QueryType::A);
+        assert_eq!(response.answers.len(), 3);
+        assert!(matches!(response.answers[0], DnsRecord::Dname { .. }));
+        assert!(matches!(
+            &response.answers[1],
+            DnsRecord::Cname { domain, host, ttl: 300 }
+                if domain == "host.old.example.com" && host == "host.new.example.com"
+        ));
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 80])]);
+    }
+
+    #[test]
+    fn resolve_request_returns_the_response_without_sending_it() {
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan\n2001:db8::10 printer.lan"),
+            ..ServerContext::default()
+        };
+        let query = to_bytes(
+            &mut DnsPacket::new()
+                .with_id(77)
+                .with_recursion_desired(true)
+                .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A)),
+        );
+
+        let mut req_buffer = BytePacketBuffer::from_bytes(&query).unwrap();
+        let src = "127.0.0.1:5300".parse().unwrap();
+        let response =
+            resolve_request(&context, &Cache::new(), &mut req_buffer, query.len(), src).unwrap();
+        assert_eq!(response.header.id, 77);
+        assert!(response.header.response);
+        assert!(response.header.recursion_desired);
+        assert_eq!(response.questions[0].name, "printer.lan");
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 10])]);
+    }
+
+    #[test]
+    fn qclass_any_questions_are_answered_from_internet_data() {
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            ..ServerContext::default()
+        };
+        let query_in_class = |class: u16| {
+            let mut query = vec![0, 5, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+            query.extend_from_slice(b"\x07printer\x03lan\x00\x00\x01");
+            query.extend_from_slice(&class.to_be_bytes());
+            query
+        };
+
+        let mut buffer = BytePacketBuffer::from_bytes(&query_in_class(255)).unwrap();
+        let mut header = DnsHeader::new();
+        header.read(&mut buffer).unwrap();
+        let mut question = DnsQuestion::new(String::new(), QueryType::Unknown(0));
+        question.read(&mut buffer).unwrap();
+        assert_eq!(question.qclass, QueryClass::Any);
+
+        let response = parse(&serve_udp(&context, &query_in_class(255)));
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 10])]);
+
+        let response = parse(&serve_udp(&context, &query_in_class(4)));
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn slow_resolutions_are_warned_about() {
+        let context = slow_forwarder_context(false);
+        let question = DnsQuestion::new("slow.example.org".to_string(), QueryType::A);
+
+        let started = Instant::now();
+        let response = ask(&context, &question.name, question.qtype);
+        let elapsed = started.elapsed();
+        assert_eq!(response.addresses(), vec![IpAddr::from([198, 51, 100, 1])]);
+
+        let warning =
+            slow_resolution_warning(Some(Duration::from_millis(100)), &question, elapsed).unwrap();
+        assert!(warning.starts_with("Slow resolution of A slow.example.org took "));
+        assert_eq!(
+            slow_resolution_warning(Some(Duration::from_secs(5)), &question, elapsed),
+            None
+        );
+        assert_eq!(slow_resolution_warning(None, &question, elapsed), None);
+    }
+
+    #[test]
+    fn oversized_packets_are_pruned_to_fit() {
+        let size = |packet: &DnsPacket| to_bytes(&mut packet.clone()).len();
+        let mut packet = DnsPacket::new()
+            .with_question(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        for i in 0..10 {
+            packet
+                .answers
+                .push(a_record("example.com", [192, 0, 2, i], 300));
+            packet
+                .authorities
+                .push(a_record("ns.example.com", [198, 51, 100, i], 300));
+            packet
+                .resources
+                .push(a_record("extra.example.com", [203, 0, 113, i], 300));
+        }
+        packet.add_edns_option(EdnsOption {
+            code: EDNS_OPTION_PADDING,
+            data: Vec::new(),
+        });
+        let full = size(&packet);
+
+        let mut trimmed = packet.clone();
+        assert!(!trimmed.fit_within(full));
+        assert_eq!(size(&trimmed), full);
+
+        assert!(trimmed.fit_within(full - 1));
+        assert!(size(&trimmed) < full);
+        assert_eq!(trimmed.resources.len(), 10);
+        assert!(trimmed.opt().is_some());
+        assert_eq!(trimmed.authorities.len(), 10);
+        assert!(!trimmed.header.truncated_message);
+
+        let mut answers_only = packet.clone();
+        answers_only.authorities.clear();
+        answers_only
+            .resources
+            .retain(|record| matches!(record, DnsRecord::Opt { .. }));
+        let budget = size(&answers_only) - 1;
+
+        let mut trimmed = packet;
+        assert!(trimmed.fit_within(budget));
+        assert!(size(&trimmed) <= budget);
+        assert!(trimmed.authorities.is_empty());
+        assert!(trimmed.opt().is_some());
+        assert_eq!(trimmed.answers.len(), 9);
+        assert!(trimmed.header.truncated_message);
+    }
+
+    #[test]
+    fn padded_queries_are_read_and_get_padded_responses() {
+        let mut request = DnsPacket::new()
+            .with_id(3)
+            .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A));
+        request.add_edns_option(EdnsOption {
+            code: EDNS_OPTION_PADDING,
+            data: vec![0; 20],
+        });
+        let query = to_bytes(&mut request);
+        assert!(parse(&query).has_edns_option(EDNS_OPTION_PADDING));
+
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            padding_block: Some(128),
+            ..ServerContext::default()
+        };
+        let data = serve_udp(&context, &query);
+        assert_eq!(data.len(), 128);
+        let response = parse(&data);
+        assert!(response.has_edns_option(EDNS_OPTION_PADDING));
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 10])]);
+
+        let unpadded = to_bytes(
+            &mut DnsPacket::new()
+                .with_id(4)
+                .with_question(DnsQuestion::new("printer.lan".to_string(), QueryType::A)),
+        );
+        let response = parse(&serve_udp(&context, &unpadded));
+        assert!(!response.has_edns_option(EDNS_OPTION_PADDING));
+    }
+
+    #[test]
+    fn lookups_honour_the_configured_timeout_and_retries() {
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let server = (Ipv4Addr::LOCALHOST, silent.local_addr().unwrap().port());
+        let config = ResolverConfig {
+            timeout: Duration::from_millis(100),
+            ..ResolverConfig::default()
+        };
+
+        let started = Instant::now();
+        assert!(lookup_once("example.com", QueryType::A, server, false, &config).is_err());
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < UPSTREAM_TIMEOUT, "{:?}", elapsed);
+
+        let config = ResolverConfig {
+            retries: 2,
+            ..config
+        };
+        assert!(lookup_once("example.com", QueryType::A, server, false, &config).is_err());
+
+        // One query from the first lookup, then three from the second.
+        silent
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut data = [0; MAX_BUFFER_SIZE];
+        let mut received = 0;
+        while silent.recv_from(&mut data).is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 4);
+    }
+
+    #[test]
+    fn dual_stack_names_resolve_to_socket_addresses() {
+        let port = mock_upstream(|request| {
+            let mut response = dual_stack_packet();
+            let qtype = request.questions[0].qtype;
+            response
+                .answers
+                .retain(|record| record.query_type() == qtype);
+            let mut response = answer(request, response.answers);
+            response.header.authoritative_answer = true;
+            to_bytes(&mut response)
+        });
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+
+        let addrs = resolve_socket_addrs_with("Example.com.", 8080, &config).unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                "192.0.2.1:8080".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:8080".parse().unwrap(),
+            ]
+        );
+        assert_eq!(
+            resolve_socket_addrs_with("2001:db8::2", 443, &config).unwrap(),
+            vec!["[2001:db8::2]:443".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn authoritative_only_servers_clear_ra() {
+        let context = ServerContext {
+            authoritative_only: true,
+            ..ServerContext::default()
+        };
+        let mut zone = Zone::new("example.com", soa_record("example.com", 1));
+        zone.records
+            .push(a_record("www.example.com", [192, 0, 2, 1], 60));
+        context.zones.write().unwrap().push(zone);
+
+        let response = ask(&context, "www.example.com", QueryType::A);
+        assert!(response.header.authoritative_answer);
+        assert!(!response.header.recursion_available);
+        assert_eq!(response.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+
+        let response = ask(&context, "www.example.org", QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+        assert!(!response.header.recursion_available);
+
+        let context = ServerContext {
+            hosts: parse_hosts("192.0.2.10 printer.lan"),
+            ..ServerContext::default()
+        };
+        assert!(
+            ask(&context, "printer.lan", QueryType::A)
+                .header
+                .recursion_available
+        );
+    }
+
+    #[test]
+    fn glue_is_picked_out_per_nameserver() {
+        let mut response = glued_referral();
+        response.resources.push(DnsRecord::Aaaa {
+            domain: "B.ns.test".to_string(),
+            addr: "2001:db8::53".parse().unwrap(),
+            ttl: 300,
+        });
+
+        assert_eq!(
+            response.glue_for("a.ns.test"),
+            vec![IpAddr::from([192, 0, 2, 1])]
+        );
+        assert_eq!(
+            response.glue_for("b.ns.test"),
+            vec![
+                IpAddr::from([192, 0, 2, 2]),
+                "2001:db8::53".parse::<IpAddr>().unwrap(),
+            ]
+        );
+        assert!(response.glue_for("d.ns.test").is_empty());
+    }
+
+    #[test]
+    fn cert_records_round_trip() {
+        let record = DnsRecord::Cert {
+            domain: "user.example.com".to_string(),
+            cert_type: 1,
+            key_tag: 0x1234,
+            algorithm: 8,
+            certificate: (0..64).collect(),
+            ttl: 3600,
+        };
+        assert_eq!(round_trip(&record), record);
+
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer, QueryClass::In).unwrap();
+        let rdata_start = buffer.position() - 69;
+        assert_eq!(
+            &buffer.buffer[rdata_start - 2..rdata_start + 5],
+            &[0, 69, 0, 1, 0x12, 0x34, 8]
+        );
+    }
+
+    #[test]
+    fn query_contexts_record_the_delegation_chain() {
+        let port = mock_upstream(|request| {
+            let mut response = referral(request, "com", "a.gtld.test");
+            response
+                .resources
+                .push(a_record("a.gtld.test", [127, 0, 0, 2], 300));
+            to_bytes(&mut response)
+        });
+        mock_upstream_at((Ipv4Addr::new(127, 0, 0, 2), port), |request| {
+            let mut response = referral(request, "example.com", "ns.example.com");
+            response
+                .resources
+                .push(a_record("ns.example.com", [127, 0, 0, 3], 300));
+            to_bytes(&mut response)
+        });
+        mock_upstream_at((Ipv4Addr::new(127, 0, 0, 3), port), |request| {
+            let name = request.questions[0].name.clone();
+            let mut response = answer(request, vec![a_record(&name, [192, 0, 2, 1], 300)]);
+            response.header.authoritative_answer = true;
+            to_bytes(&mut response)
+        });
+
+        let config = ResolverConfig {
+            root_servers: vec![Ipv4Addr::LOCALHOST],
+            upstream_port: port,
+            ..test_config()
+        };
+        let query = QueryContext::new(
+            9,
+            DnsQuestion::new("www.example.com".to_string(), QueryType::A),
+        );
+        let response = lookup(&query, &config).unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(query.delegations(), vec!["com", "example.com"]);
+        assert_eq!(
+            query.servers(),
+            vec![
+                Ipv4Addr::LOCALHOST,
+                Ipv4Addr::new(127, 0, 0, 2),
+                Ipv4Addr::new(127, 0, 0, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn resolution_gives_up_at_the_configured_budget() {
+        let asked = Arc::new(Mutex::new(0));
+        let port = {
+            let asked = asked.clone();
+            mock_upstream(move |request| {
+                *asked.lock().unwrap() += 1;
+                let name = request.questions[0].name.clone();
+                to_bytes(&mut answer(
+                    request,
+                    vec![a_record(&name, [192, 0, 2, 1], 300)],
+                ))
+            })
+        };
+        let context = ServerContext {
+            resolver: ResolverConfig {
+                root_servers: vec![Ipv4Addr::LOCALHOST],
+                upstream_port: port,
+                budget: Duration::ZERO,
+                ..test_config()
+            },
+            ..ServerContext::default()
+        };
+
+        let response = ask(&context, "www.example.org", QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::ServFail);
+        assert_eq!(*asked.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn repeated_names_are_written_out_in_full() {
+        let mut packet = DnsPacket::new()
+            .with_id(1)
+            .with_question(DnsQuestion::new("a.example".to_string(), QueryType::A));
+        packet.header.response = true;
+        packet
+            .answers
+            .push(a_record("a.example", [192, 0, 2, 1], 60));
+
+        let mut expected = vec![0, 1, 0x80, 0, 0, 1, 0, 1, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x01a\x07example\x00\x00\x01\x00\x01");
+        expected.extend_from_slice(b"\x01a\x07example\x00\x00\x01\x00\x01");
+        expected.extend_from_slice(&[0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        assert_eq!(to_bytes(&mut packet), expected);
+    }
 }
@@ -1,12 +1,148 @@
-use std::net::UdpSocket;
-use toy_dns_server::handle_query;
+use std::env;
+use std::fs::OpenOptions;
+use std::thread;
+use toy_dns_server::{
+    bind_listeners, bind_sockets, handle_query, handle_query_tcp, load_hosts, Blocklist, Cache,
+    ServerContext, View, Zone, OPCODE_QUERY, OPCODE_UPDATE,
+};
 
 fn main() {
-    let socket = UdpSocket::bind(("0.0.0.0", 2053)).expect("couldn't bind to address");
+    let sockets = bind_sockets(2053).expect("couldn't bind to address");
+    let listeners = bind_listeners(2053).expect("couldn't bind to address");
 
-    loop {
-        if let Err(e) = handle_query(&socket) {
-            eprintln!("An error occurred: {}", e);
+    let mut context = ServerContext::default();
+    if let Ok(path) = env::var("HOSTS_FILE") {
+        context.hosts = load_hosts(path).expect("couldn't load hosts file");
+    }
+    if let Ok(list) = env::var("VIEWS") {
+        for spec in list.split(',') {
+            let (subnet, path) = spec
+                .split_once('=')
+                .expect("VIEWS entries should look like subnet=hosts-file");
+            context.views.push(View {
+                subnet: subnet.parse().expect("couldn't parse view subnet"),
+                hosts: load_hosts(path).expect("couldn't load view hosts file"),
+            });
         }
     }
+    if let Ok(list) = env::var("TRUSTED_ECS_SOURCES") {
+        context.trusted_ecs_sources = list
+            .split(',')
+            .map(|subnet| subnet.parse().expect("couldn't parse trusted ECS source"))
+            .collect();
+    }
+    if let Ok(path) = env::var("BLOCKLIST_FILE") {
+        context.blocklist = Blocklist::load(path).expect("couldn't load blocklist");
+    }
+    if let Ok(list) = env::var("ALLOW_UPDATE") {
+        context.allowed_opcodes = Some(vec![OPCODE_QUERY, OPCODE_UPDATE]);
+        context.update_sources = list
+            .split(',')
+            .map(|subnet| subnet.parse().expect("couldn't parse update source subnet"))
+            .collect();
+    }
+    if let Ok(path) = env::var("CAPTURE_FILE") {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("couldn't open capture file");
+        context.capture = Some(file);
+    }
+    if let Ok(list) = env::var("FORWARDERS") {
+        context.forwarders = list
+            .split(',')
+            .map(|forwarder| match forwarder.split_once(':') {
+                Some((addr, port)) => (
+                    addr.parse().expect("couldn't parse forwarder address"),
+                    port.parse().expect("couldn't parse forwarder port"),
+                ),
+                None => (
+                    forwarder.parse().expect("couldn't parse forwarder address"),
+                    53,
+                ),
+            })
+            .collect();
+    }
+    context.resolver.spoofing_protection = env::var("SPOOFING_PROTECTION").is_ok();
+    if let Ok(millis) = env::var("UPSTREAM_TIMEOUT_MS") {
+        let millis = millis.parse().expect("couldn't parse upstream timeout");
+        context.resolver.timeout = std::time::Duration::from_millis(millis);
+    }
+    if let Ok(retries) = env::var("UPSTREAM_RETRIES") {
+        context.resolver.retries = retries.parse().expect("couldn't parse upstream retries");
+    }
+    if let Ok(millis) = env::var("RESOLUTION_BUDGET_MS") {
+        let millis = millis.parse().expect("couldn't parse resolution budget");
+        context.resolver.budget = std::time::Duration::from_millis(millis);
+    }
+    if let Ok(size) = env::var("CACHE_SIZE") {
+        context.resolver.cache_size = size.parse().expect("couldn't parse cache size");
+    }
+    if let Ok(addr) = env::var("OUTBOUND_ADDRESS") {
+        context.resolver.source = addr.parse().expect("couldn't parse outbound address");
+    }
+    if let Ok(spec) = env::var("SECONDARY_ZONE") {
+        let (origin, primary) = spec
+            .split_once('@')
+            .expect("SECONDARY_ZONE should look like origin@address:port");
+        let primary = primary.parse().expect("couldn't parse primary address");
+        let zone =
+            Zone::transfer(origin, primary, &context.resolver).expect("couldn't transfer zone");
+        context.zones.get_mut().unwrap().push(zone);
+    }
+    if let Ok(max) = env::var("MAX_TCP_CONNECTIONS") {
+        context.max_tcp_connections = Some(max.parse().expect("couldn't parse connection limit"));
+    }
+    if let Ok(max) = env::var("MAX_ANSWERS") {
+        context.max_answers = Some(max.parse().expect("couldn't parse answer limit"));
+    }
+    if let Ok(millis) = env::var("SLOW_RESOLUTION_MS") {
+        let millis = millis
+            .parse()
+            .expect("couldn't parse slow resolution threshold");
+        context.slow_resolution = Some(std::time::Duration::from_millis(millis));
+    }
+    if let Ok(block) = env::var("PADDING_BLOCK") {
+        context.padding_block = Some(block.parse().expect("couldn't parse padding block size"));
+    }
+    context.chaos_version = env::var("CHAOS_VERSION").ok();
+    context.chaos_hostname = env::var("CHAOS_HOSTNAME").ok();
+    #[cfg(debug_assertions)]
+    if let Ok(millis) = env::var("RESPONSE_DELAY_MS") {
+        let millis = millis.parse().expect("couldn't parse response delay");
+        context.response_delay = Some(std::time::Duration::from_millis(millis));
+    }
+
+    let context = &context;
+    let cache = &Cache::with_capacity(context.resolver.cache_size);
+    thread::scope(|scope| {
+        for socket in &sockets {
+            scope.spawn(move || loop {
+                if let Err(e) = handle_query(socket, context, cache) {
+                    eprintln!("An error occurred: {:#}", e);
+                }
+            });
+        }
+
+        for listener in &listeners {
+            scope.spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("An error occurred: {:#}", e);
+                            continue;
+                        }
+                    };
+
+                    scope.spawn(move || {
+                        if let Err(e) = handle_query_tcp(&mut stream, context, cache) {
+                            eprintln!("An error occurred: {:#}", e);
+                        }
+                    });
+                }
+            });
+        }
+    });
 }